@@ -39,15 +39,67 @@ class PathApi:
                                  "options": self.options.opts()}}
         return self.api_request(request)
 
+    def TraceIntercept(self, world, sx, sy, sz, target, speed):
+        request = {"TraceIntercept": {"world": world, "sx": sx, "sy": sy, "sz": sz, "target": target, "speed": speed,
+                                      "options": self.options.opts()}}
+        return self.api_request(request)
+
+    def TraceFlee(self, world, sx, sy, sz, threats, distance, threat_radius=None):
+        request = {"TraceFlee": {"world": world, "sx": sx, "sy": sy, "sz": sz, "threats": threats, "distance": distance,
+                                 "threat_radius": threat_radius, "options": self.options.opts()}}
+        return self.api_request(request)
+
+    def TraceTour(self, world, sx, sy, sz, waypoints, closed=None):
+        request = {"TraceTour": {"world": world, "sx": sx, "sy": sy, "sz": sz, "waypoints": waypoints, "closed": closed,
+                                 "options": self.options.opts()}}
+        return self.api_request(request)
+
+    def LineOfSight(self, world, sx, sy, sz, dx, dy, dz):
+        request = {"LineOfSight": {"world": world, "sx": sx, "sy": sy, "sz": sz, "dx": dx, "dy": dy, "dz": dz}}
+        return self.api_request(request)
+
+    def FieldOfView(self, world, x, y, z, radius=None):
+        request = {"FieldOfView": {"world": world, "x": x, "y": y, "z": z, "radius": radius}}
+        return self.api_request(request)
+
     def RenderArea(self, world, left, top, right, bottom, points, color=None):
         request = {
             "RenderArea": {"world": world, "points": points, "color": color, "left": left, "top": top, "right": right,
                            "bottom": bottom}}
         return self.api_request(request)
 
+    def ExportWalkability(self, world, left=None, top=None, right=None, bottom=None):
+        request = {"ExportWalkability": {"world": world, "left": left, "top": top, "right": right, "bottom": bottom}}
+        return self.api_request(request)
+
+    def StepCacheStats(self, world):
+        request = {"StepCacheStats": {"world": world}}
+        return self.api_request(request)
+
+    def ExportNavMesh(self, world, left, top, right, bottom, flags_walk=None, flags_ignore=None):
+        request = {"ExportNavMesh": {"world": world, "left": left, "top": top, "right": right, "bottom": bottom,
+                                     "flags_walk": flags_walk, "flags_ignore": flags_ignore}}
+        return self.api_request(request)
+
+    def ResolveZ(self, world, x, y, z=None):
+        request = {"ResolveZ": {"world": world, "x": x, "y": y, "z": z}}
+        return self.api_request(request)
+
+    def TileInfo(self, world, x, y):
+        request = {"TileInfo": {"world": world, "x": x, "y": y}}
+        return self.api_request(request)
+
+    def TileDataGet(self, land, id):
+        request = {"TileDataGet": {"land": land, "id": id}}
+        return self.api_request(request)
+
+    def TileDataFind(self, name, land=None):
+        request = {"TileDataFind": {"name": name, "land": land}}
+        return self.api_request(request)
+
     def api_request(self, request):
         reply = requests.post(self.url, json=request)
-        if "RenderArea" in request:
+        if "RenderArea" in request or "ExportWalkability" in request:
             return reply.content
         else:
             return reply.json()
@@ -59,7 +111,9 @@ class TraceOptions:
                  accuracy_x=None, accuracy_y=None, accuracy_z=None,
                  cost_turn=None, cost_move_straight=None, cost_move_diagonal=None, cost_limit=None,
                  heuristic_distance=None, heuristic_straight=None, heuristic_diagonal=None,
-                 allow_diagonal_move=None, all_points=None, open_door=None, time_limit=None
+                 allow_diagonal_move=None, all_points=None, open_door=None, time_limit=None, node_limit=None, use_navmesh=None,
+                 corridor_margin=None, deterministic=None, stats=None,
+                 partial=None, snap_z=None
                  ):
         self.left = left
         self.top = top
@@ -83,6 +137,13 @@ class TraceOptions:
         self.all_points = all_points
         self.open_door = open_door
         self.time_limit = time_limit
+        self.node_limit = node_limit
+        self.use_navmesh = use_navmesh
+        self.corridor_margin = corridor_margin
+        self.deterministic = deterministic
+        self.stats = stats
+        self.partial = partial
+        self.snap_z = snap_z
 
     def opts(self):
         return {k: v for (k, v) in self.__dict__.items() if v is not None}
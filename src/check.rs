@@ -0,0 +1,330 @@
+use std::fs;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use log::{error, info, warn};
+
+use crate::mul::{Multi, MulError, TileData};
+use crate::mul::art::ArtLoader;
+use crate::mul::mapdata::MUL_MAP_BLOCK_SIZE;
+use crate::mul::mulreader::{get_file_path_ci, get_world_file_path};
+use crate::mul::staticdata::{Static, STATIC_TILE_SIZE};
+use crate::mul::uop::{get_legacy_uop_path, read_legacy_mul};
+use crate::mul::verdata::Verdata;
+use crate::mul::LOOKUP_IDX_RECORD_SIZE;
+use crate::world::world::StaticWorld;
+use crate::world::world_model::FacetSpec;
+
+
+/// problems found by `check_data_files`. Errors prevent the server from starting or a world from loading,
+/// warnings are inconsistencies the server can work with
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    pub errors: usize,
+    pub warnings: usize,
+}
+
+impl CheckReport {
+    fn error(&mut self, message: impl AsRef<str>) {
+        error!("{}", message.as_ref());
+        self.errors += 1;
+    }
+
+    fn warn(&mut self, message: impl AsRef<str>) {
+        warn!("{}", message.as_ref());
+        self.warnings += 1;
+    }
+}
+
+
+/// checks the data files the same way as they are loaded, without loading the worlds,
+/// and logs the detected formats and found problems
+pub fn check_data_files(data_path: &Path, facets: &[FacetSpec]) -> CheckReport {
+    info!("checking data files in {}", data_path.display());
+    let mut report = CheckReport::default();
+
+    let static_tiles = check_tiledata(data_path, &mut report);
+    check_colors(data_path, static_tiles, &mut report);
+    check_multis(data_path, &mut report);
+
+    let verdata = match Verdata::read(data_path) {
+        Ok(Some(verdata)) => {
+            info!("{}: patches of tiledata, multis and art are applied", verdata.path().display());
+            Some(verdata)
+        }
+
+        Ok(None) => None,
+        Err(err) => {
+            report.warn(format!("verdata.mul is ignored: {err}"));
+            None
+        }
+    };
+    check_art(data_path, verdata.as_ref(), &mut report);
+
+    for spec in facets {
+        check_world(data_path, spec, &mut report);
+    }
+
+    match (report.errors, report.warnings) {
+        (0, 0) => info!("no problems found"),
+        (errors, warnings) => info!("found {errors} errors and {warnings} warnings"),
+    }
+
+    report
+}
+
+
+/// returns the count of static tiles, if the format of tiledata is known
+fn check_tiledata(data_path: &Path, report: &mut CheckReport) -> Option<usize> {
+    let path = get_file_path_ci(data_path, "tiledata.mul");
+    let uop_path = get_legacy_uop_path(data_path, "tiledata");
+
+    let (path, size) = if path.exists() || !uop_path.exists() {
+        let size = fs::metadata(&path).map(|meta| meta.len()).map_err(|err| MulError::io(&path, err));
+        (path, size)
+    } else {
+        let size = read_legacy_mul(data_path, "tiledata").map(|data| data.len() as u64).map_err(|err| MulError::io(&uop_path, err));
+        (uop_path, size)
+    };
+
+    let file_len = match size {
+        Ok(file_len) => file_len,
+        Err(err) => {
+            report.error(err.to_string());
+            return None
+        }
+    };
+
+    let layout = match TileData::layout(file_len) {
+        Some(layout) => layout,
+        None => {
+            report.error(format!("{}: {file_len} bytes are not enough for land tiles", path.display()));
+            return None
+        }
+    };
+
+    let format = if layout.is7090 { "7090" } else { "pre-7090" };
+    info!("{}: {format} format, {} static tiles", path.display(), layout.static_tiles);
+    if layout.trailing_bytes != 0 {
+        report.warn(format!("{}: {} bytes after the last group of static tiles are ignored", path.display(), layout.trailing_bytes));
+    }
+
+    match TileData::read(data_path) {
+        Ok(_) => Some(layout.static_tiles),
+        Err(err) => {
+            report.error(err.to_string());
+            None
+        }
+    }
+}
+
+
+fn check_colors(data_path: &Path, static_tiles: Option<usize>, report: &mut CheckReport) {
+    let path = get_file_path_ci(data_path, "Radarcol.mul");
+    let colors = match fs::metadata(&path) {
+        Ok(meta) => meta.len() as usize / 2,
+        Err(err) => {
+            report.error(MulError::io(&path, err).to_string());
+            return
+        }
+    };
+
+    info!("{}: {colors} colors", path.display());
+
+    // colors of land tiles are followed by colors of static tiles, but there are never more than 0x10000 colors
+    let expected = (0x4000 + static_tiles.unwrap_or(0)).min(0x10000);
+    if colors < expected {
+        report.warn(format!("{}: {} tiles have no colors", path.display(), expected - colors));
+    }
+}
+
+
+fn check_multis(data_path: &Path, report: &mut CheckReport) {
+    let path = get_file_path_ci(data_path, "multi.mul");
+    let uop_path = get_file_path_ci(data_path, "MultiCollection.uop");
+
+    if path.exists() || !uop_path.exists() {
+        match Multi::read_index(&get_file_path_ci(data_path, "multi.idx")) {
+            Ok((index, is7090)) => {
+                let format = if is7090 { "7090" } else { "pre-7090" };
+                let multis = index.iter().filter(|&&(offset, _)| offset != 0xFFFF_FFFF).count();
+                info!("{}: {format} format, {multis} multi-objects", path.display());
+            }
+
+            Err(err) => {
+                report.error(err.to_string());
+                return
+            }
+        }
+    } else {
+        info!("{}: UOP format", uop_path.display());
+    }
+
+    if let Err(err) = Multi::read(data_path) {
+        report.error(err.to_string());
+    }
+}
+
+
+/// art is not needed by the server, so broken art files are only a warning
+fn check_art(data_path: &Path, verdata: Option<&Verdata>, report: &mut CheckReport) {
+    let index_path = get_file_path_ci(data_path, "artidx.mul");
+    let uop_path = get_legacy_uop_path(data_path, "art");
+    let path = match (index_path.exists(), uop_path.exists()) {
+        (true, _) => index_path,
+        (false, true) => uop_path,
+        (false, false) => {
+            info!("no art files");
+            return
+        }
+    };
+
+    match ArtLoader::read(data_path) {
+        Ok(mut art) => {
+            if let Some(verdata) = verdata {
+                match art.apply_verdata(verdata) {
+                    Ok(count) => info!("{count} sprites are patched from {}", verdata.path().display()),
+                    Err(err) => report.warn(format!("unable to apply art patches from {}: {err}", verdata.path().display())),
+                }
+            }
+
+
+            let sprites = art.index.iter().filter(|entry| entry.is_some()).count();
+            info!("{}: {sprites} sprites", path.display());
+
+            // the data itself is read on demand, so at least one sprite is decoded
+            if let Some(art_idx) = art.index.iter().position(|entry| entry.is_some()) {
+                if let Err(err) = art.read_art(art_idx as u32) {
+                    report.warn(format!("{}: unable to decode sprite {art_idx}: {err}", path.display()));
+                }
+            }
+        }
+
+        Err(err) => report.warn(MulError::io(&path, err).to_string()),
+    }
+}
+
+
+fn check_world(data_path: &Path, spec: &FacetSpec, report: &mut CheckReport) {
+    let (world, files, width) = (spec.world, spec.file_index(), spec.width);
+    let (use_mul, width_blocks, height_blocks) = match StaticWorld::probe(data_path, files, spec.format, width, spec.height) {
+        Ok(Some(probe)) => probe,
+        Ok(None) => {
+            info!("world {world}: no map files");
+            return
+        }
+
+        Err(err) => {
+            report.error(format!("world {world}: {err}"));
+            return
+        }
+    };
+
+    let extension = if use_mul { ".mul" } else { "LegacyMUL.uop" };
+    let map_path = get_world_file_path(data_path, "map", files, extension);
+    let format = if use_mul { "MUL" } else { "UOP" };
+    info!("world {world}: {} {format}, {width_blocks}x{height_blocks} blocks", map_path.display());
+
+    if width_blocks != width {
+        report.warn(format!("world {world}: the map is {width_blocks} blocks wide, {width} expected"));
+    }
+
+    if use_mul {
+        let blocks_size = (width_blocks * height_blocks * MUL_MAP_BLOCK_SIZE) as u64;
+        if let Ok(meta) = fs::metadata(&map_path) {
+            if meta.len() != blocks_size {
+                report.warn(format!("world {world}: {} bytes after the last block of {} are ignored", meta.len() - blocks_size, map_path.display()));
+            }
+        }
+    }
+
+    check_statics(data_path, world, files, width_blocks * height_blocks, report);
+
+    let patches: Vec<_> = ["mapdifl", "mapdif", "stadifl", "stadifi", "stadif"]
+        .iter()
+        .map(|name| get_file_path_ci(data_path, &format!("{name}{files}.mul")))
+        .filter(|path| path.exists())
+        .collect();
+
+    if !patches.is_empty() {
+        let names: Vec<_> = patches.iter().filter_map(|path| path.file_name()).map(|name| name.to_string_lossy()).collect();
+        info!("world {world}: patches {} are applied with --map-patches", names.join(", "));
+    }
+}
+
+
+/// returns the paths of statics and staidx, the size of statics and contents of staidx
+fn read_statics_files(data_path: &Path, world: u8) -> Result<(PathBuf, u64, PathBuf, Vec<u8>), MulError> {
+    let path = get_world_file_path(data_path, "statics", world, ".mul");
+    let index_path = get_world_file_path(data_path, "staidx", world, ".mul");
+
+    let uop_path = get_legacy_uop_path(data_path, &format!("statics{world}"));
+    if !path.exists() && uop_path.exists() {
+        let index_uop_path = get_legacy_uop_path(data_path, &format!("staidx{world}"));
+        let statics = read_legacy_mul(data_path, &format!("statics{world}")).map_err(|err| MulError::io(&uop_path, err))?;
+        let index = read_legacy_mul(data_path, &format!("staidx{world}")).map_err(|err| MulError::io(&index_uop_path, err))?;
+        return Ok((uop_path, statics.len() as u64, index_uop_path, index))
+    }
+
+    let size = fs::metadata(&path).map_err(|err| MulError::io(&path, err))?.len();
+    let index = fs::read(&index_path).map_err(|err| MulError::io(&index_path, err))?;
+    Ok((path, size, index_path, index))
+}
+
+
+fn check_statics(data_path: &Path, world: u8, files: u8, blocks: usize, report: &mut CheckReport) {
+    let (path, size, index_path, index) = match read_statics_files(data_path, files) {
+        Ok(files) => files,
+        Err(err) => {
+            report.error(format!("world {world}: {err}"));
+            return
+        }
+    };
+
+    if !(size as usize).is_multiple_of(STATIC_TILE_SIZE) {
+        report.error(format!("world {world}: size of {} is not a multiple of {STATIC_TILE_SIZE} bytes", path.display()));
+        return
+    }
+
+    let index_blocks = index.len() / LOOKUP_IDX_RECORD_SIZE;
+    if index_blocks < blocks {
+        report.error(format!("world {world}: {} has {index_blocks} blocks, {blocks} expected", index_path.display()));
+        return
+    }
+
+    if index.len() != blocks * LOOKUP_IDX_RECORD_SIZE {
+        report.warn(format!("world {world}: {} bytes after the last block of {} are ignored",
+            index.len() - blocks * LOOKUP_IDX_RECORD_SIZE, index_path.display()));
+    }
+
+    let ranges = match Static::read_index(&mut Cursor::new(index), blocks) {
+        Ok(ranges) => ranges,
+        Err(err) => {
+            report.error(format!("world {world}: {}", MulError::io(&index_path, err)));
+            return
+        }
+    };
+
+    let (mut filled, mut outside, mut unaligned) = (0, 0, 0);
+    for (offset, length) in ranges {
+        if offset == 0xFFFF_FFFF {
+            continue
+        }
+
+        filled += 1;
+        if offset as u64 + length as u64 > size {
+            outside += 1;
+        } else if !(offset as usize).is_multiple_of(STATIC_TILE_SIZE) || !(length as usize).is_multiple_of(STATIC_TILE_SIZE) {
+            unaligned += 1;
+        }
+    }
+
+    info!("world {world}: {} {} tiles in {filled} of {blocks} blocks", path.display(), size as usize / STATIC_TILE_SIZE);
+
+    if outside != 0 {
+        report.error(format!("world {world}: {outside} blocks of {} are outside of {}", index_path.display(), path.display()));
+    }
+
+    if unaligned != 0 {
+        report.warn(format!("world {world}: {unaligned} blocks of {} are not aligned to statics tiles", index_path.display()));
+    }
+}
@@ -16,11 +16,16 @@ use hyper::service::{make_service_fn, service_fn};
 use serde::{Deserialize, Serialize};
 
 use image::{ImageBuffer, Rgb};
-use log::{error, info};
+use log::{error, info, warn};
 
-use crate::mul::tiledata::MulTileFlags;
+use crate::mul::tiledata::{LandTileData, MulTileFlags, StaticTileData};
 use crate::world::{WorldModel, WorldSurveyor};
-use crate::world::tiles::TopLevelItem;
+use crate::world::surveyor::{Trace, Tour, MAX_TOUR_WAYPOINTS};
+use crate::world::export::export_walkability;
+use crate::world::sight::MAX_FOV_RADIUS;
+use crate::world::navmesh::NavNode;
+use crate::world::{TileShape, TileType};
+use crate::world::tiles::{SourcedTile, TileSource, TopLevelItem};
 
 
 #[derive(Serialize, Deserialize, Debug, Clone, Copy)]
@@ -39,17 +44,37 @@ pub enum TileFlags {
     HoverOver,
     Door,
     Wall,
+    NoHouse,
+    AlphaBlend,
+    UseNewArt,
+    ArtUsed,
+    Unused,
+    NoShadow,
+    PixelBleed,
+    PlayAnimOnce,
+    NoDraw,
+    MultiMovable,
 }
 
 impl TileFlags {
-    pub fn to_mul_flags(&self) -> u32 {
+    pub fn to_mul_flags(&self) -> u64 {
         match self {
-            TileFlags::Impassable => MulTileFlags::Impassable as u32,
-            TileFlags::Surface => MulTileFlags::Surface as u32,
-            TileFlags::Wet => MulTileFlags::Wet as u32,
-            TileFlags::HoverOver => MulTileFlags::HoverOver as u32,
-            TileFlags::Door => MulTileFlags::Door as u32,
-            TileFlags::Wall => MulTileFlags::Wall as u32,
+            TileFlags::Impassable => MulTileFlags::Impassable as u64,
+            TileFlags::Surface => MulTileFlags::Surface as u64,
+            TileFlags::Wet => MulTileFlags::Wet as u64,
+            TileFlags::HoverOver => MulTileFlags::HoverOver as u64,
+            TileFlags::Door => MulTileFlags::Door as u64,
+            TileFlags::Wall => MulTileFlags::Wall as u64,
+            TileFlags::NoHouse => MulTileFlags::NoHouse as u64,
+            TileFlags::AlphaBlend => MulTileFlags::AlphaBlend as u64,
+            TileFlags::UseNewArt => MulTileFlags::UseNewArt as u64,
+            TileFlags::ArtUsed => MulTileFlags::ArtUsed as u64,
+            TileFlags::Unused => MulTileFlags::Unused as u64,
+            TileFlags::NoShadow => MulTileFlags::NoShadow as u64,
+            TileFlags::PixelBleed => MulTileFlags::PixelBleed as u64,
+            TileFlags::PlayAnimOnce => MulTileFlags::PlayAnimOnce as u64,
+            TileFlags::NoDraw => MulTileFlags::NoDraw as u64,
+            TileFlags::MultiMovable => MulTileFlags::MultiMovable as u64,
         }
     }
 }
@@ -72,12 +97,20 @@ pub struct TraceOptions {
     pub all_points: Option<bool>,
     pub allow_diagonal_move: Option<bool>,
     pub time_limit: Option<isize>,
+    pub node_limit: Option<isize>,
+    pub use_navmesh: Option<bool>,
+    pub corridor_margin: Option<isize>,
+    pub deterministic: Option<bool>,
+    pub stats: Option<bool>,
+    pub partial: Option<bool>,
+    pub snap_z: Option<bool>,
     // movement cost
     pub cost_limit: Option<isize>,
     pub cost_turn: Option<isize>,
     pub cost_move_straight: Option<isize>,
     pub cost_move_diagonal: Option<isize>,
     pub cost_move_multi: Option<isize>,
+    pub cost_threat: Option<isize>,
     // heuristic
     pub heuristic_distance: Option<DistanceFunc>,
     pub heuristic_straight: Option<isize>,
@@ -103,12 +136,20 @@ impl TraceOptions {
             all_points: None,
             allow_diagonal_move: None,
             time_limit: None,
+            node_limit: None,
+            use_navmesh: None,
+            corridor_margin: None,
+            deterministic: None,
+            stats: None,
+            partial: None,
+            snap_z: None,
 
             cost_limit: None,
             cost_turn: None,
             cost_move_straight: None,
             cost_move_diagonal: None,
             cost_move_multi: None,
+            cost_threat: None,
 
             heuristic_distance: None,
             heuristic_straight: None,
@@ -129,15 +170,175 @@ pub struct Item {
 }
 
 
+/// the result of the path search for the caller
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum TraceStatus {
+    Reached,            // the path leads to the goal
+    PartialNearest,     // the goal is unreachable, the path leads to the nearest point
+    StartBlocked,       // it is impossible to make a single step from the start
+    NoData,             // nothing to search, e.g. the world is not loaded
+}
+
+
+/// why the search has stopped
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum Termination {
+    Found,
+    TimeLimit,
+    NodeLimit,
+    CostLimit,
+    Exhausted,
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TraceStats {
+    pub nodes_expanded: usize,
+    pub nodes_pushed: usize,
+    pub cache_hits: usize,
+    pub peak_frontier: usize,
+    pub elapsed_ms: u64,
+    pub termination: Termination,
+    pub reached_goal: bool,     // the path leads to the goal, not just to the nearest point
+}
+
+impl TraceStats {
+    pub fn new() -> Self {
+        Self {
+            nodes_expanded: 0,
+            nodes_pushed: 0,
+            cache_hits: 0,
+            peak_frontier: 0,
+            elapsed_ms: 0,
+            termination: Termination::Exhausted,
+            reached_goal: false,
+        }
+    }
+
+    /// adds the statistics of the next search, the result is defined by the last one
+    pub fn merge(&mut self, next: &TraceStats) {
+        self.nodes_expanded += next.nodes_expanded;
+        self.nodes_pushed += next.nodes_pushed;
+        self.cache_hits += next.cache_hits;
+        self.peak_frontier = self.peak_frontier.max(next.peak_frontier);
+        self.elapsed_ms += next.elapsed_ms;
+        self.termination = next.termination;
+        self.reached_goal = next.reached_goal;
+    }
+}
+
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Area {
+    pub left: isize,
+    pub top: isize,
+    pub right: isize,
+    pub bottom: isize,
+}
+
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Point {
     pub x: isize,
     pub y: isize,
     pub z: i8,
+    #[serde(default)]
     pub w: isize,
 }
 
 
+/// the tile blocking the line of sight
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SightBlocker {
+    pub x: isize,
+    pub y: isize,
+    pub z: i8,
+    pub tile: u16,
+    pub land: bool,
+}
+
+
+/// kind of the `TileShape` of a tile
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+pub enum TileShapeKind {
+    Slope,
+    Surface,
+    HoverOver,
+    Background,
+}
+
+
+/// one tile of the stack at some coordinates, as the pathfinding sees it
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TileInfoEntry {
+    pub tile: u16,
+    pub source: TileSource,
+    pub serial: Option<u32>,
+    pub z_base: i8,
+    pub z_stand: Option<i8>,
+    pub z_top: i8,
+    pub shape: TileShapeKind,
+    pub passable: bool,
+    pub flags: u64,
+    pub flag_names: Vec<String>,
+    pub name: String,
+}
+
+
+/// the record of a land or static tile from tiledata, fields missing for land tiles are null
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TileDataEntry {
+    pub id: u16,
+    pub land: bool,
+    pub name: String,
+    pub flags: u64,
+    pub flag_names: Vec<String>,
+    pub texture_id: Option<u16>,
+    pub height: Option<u8>,
+    pub weight: Option<u8>,
+    pub quality: Option<u8>,
+    pub quantity: Option<u8>,
+    pub anim_id: Option<u16>,
+    pub hue: Option<u8>,
+}
+
+impl TileDataEntry {
+    pub fn land(id: u16, tile: &LandTileData) -> Self {
+        Self {
+            id,
+            land: true,
+            name: tile.name.clone(),
+            flags: tile.flags,
+            flag_names: MulTileFlags::names(tile.flags).into_iter().map(String::from).collect(),
+            texture_id: Some(tile.texture_id),
+            height: None,
+            weight: None,
+            quality: None,
+            quantity: None,
+            anim_id: None,
+            hue: None,
+        }
+    }
+
+    pub fn object(id: u16, tile: &StaticTileData) -> Self {
+        Self {
+            id,
+            land: false,
+            name: tile.name.clone(),
+            flags: tile.flags,
+            flag_names: MulTileFlags::names(tile.flags).into_iter().map(String::from).collect(),
+            texture_id: None,
+            height: Some(tile.height),
+            weight: Some(tile.weight),
+            quality: Some(tile.quality),
+            quantity: Some(tile.quantity),
+            anim_id: Some(tile.anim_id),
+            hue: Some(tile.hue),
+        }
+    }
+}
+
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct MultiItemPart {
     pub graphic: u16,
@@ -169,8 +370,25 @@ pub enum ApiRequest {
     Query {world: u8, left: isize, top: isize, right: isize, bottom: isize, },
 
     TracePath{world: u8, sx: isize, sy: isize, sz: i8, dx: isize, dy: isize, dz: i8, options: TraceOptions, },
+    TraceIntercept{world: u8, sx: isize, sy: isize, sz: i8, target: Vec<Point>, speed: f64, options: TraceOptions, },
+    TraceFlee{world: u8, sx: isize, sy: isize, sz: i8, threats: Vec<Point>, distance: isize, threat_radius: Option<isize>, options: TraceOptions, },
+    TraceTour{world: u8, sx: isize, sy: isize, sz: i8, waypoints: Vec<Point>, closed: Option<bool>, options: TraceOptions, },
+
+    LineOfSight{world: u8, sx: isize, sy: isize, sz: i8, dx: isize, dy: isize, dz: i8, },
+    FieldOfView{world: u8, x: isize, y: isize, z: i8, radius: Option<isize>, },
 
     RenderArea{world: u8, left: Option<isize>, top: Option<isize>, right: Option<isize>, bottom: Option<isize>, color: Option<isize>, points: Vec<Point>, },
+
+    ExportWalkability{world: u8, left: Option<isize>, top: Option<isize>, right: Option<isize>, bottom: Option<isize>, },
+    StepCacheStats{world: u8, },
+
+    ExportNavMesh{world: u8, left: isize, top: isize, right: isize, bottom: isize, flags_walk: Option<Vec<TileFlags>>, flags_ignore: Option<Vec<TileFlags>>, },
+
+    ResolveZ{world: u8, x: isize, y: isize, z: Option<i8>, },
+    TileInfo{world: u8, x: isize, y: isize, },
+
+    TileDataGet{land: bool, id: u16, },
+    TileDataFind{name: String, land: Option<bool>, },
 }
 
 
@@ -179,9 +397,26 @@ pub enum ApiResponse {
     Success { },
     Error { err: String, },
     QueryReply {items: Vec<Item>, },
-    TraceReply { points: Vec<Point>, },
+    TraceReply {
+        points: Vec<Point>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        areas: Option<Vec<Area>>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        stats: Option<TraceStats>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        status: Option<TraceStatus>,
+    },
+    TourReply { order: Vec<usize>, unreachable: Vec<usize>, broken: Vec<usize>, cost: isize, points: Vec<Point>, },
+    LineOfSightReply { visible: bool, blocker: Option<SightBlocker>, },
+    NavMeshReply { nodes: Vec<NavNode>, },
+    StepCacheReply { hits: u64, misses: u64, evictions: u64, blocks: usize, entries: usize, },
+    ResolveZReply { levels: Vec<i8>, nearest: Option<i8>, },
+    TileInfoReply { tiles: Vec<TileInfoEntry>, },
+    TileDataReply { tiles: Vec<TileDataEntry>, },
     #[serde(skip_serializing, skip_deserializing)]
     RenderReply { image: ImageBuffer<Rgb<u8>, Vec<u8>> },
+    #[serde(skip_serializing, skip_deserializing)]
+    BinaryReply { data: Vec<u8> },
 }
 
 
@@ -257,9 +492,38 @@ impl ApiHandler {
 
                     ApiRequest::TracePath{world, sx, sy, sz, dx, dy, dz, options}
                         => self.handle_trace_path(world, sx, sy, sz, dx, dy, dz, &options).await,
+                    ApiRequest::TraceIntercept{world, sx, sy, sz, target, speed, options}
+                        => self.handle_trace_intercept(world, sx, sy, sz, target, speed, &options).await,
+                    ApiRequest::TraceFlee{world, sx, sy, sz, threats, distance, threat_radius, options}
+                        => self.handle_trace_flee(world, sx, sy, sz, threats, distance, threat_radius, &options).await,
+                    ApiRequest::TraceTour{world, sx, sy, sz, waypoints, closed, options}
+                        => self.handle_trace_tour(world, sx, sy, sz, waypoints, closed, &options).await,
+
+                    ApiRequest::LineOfSight{world, sx, sy, sz, dx, dy, dz}
+                        => self.handle_line_of_sight(world, sx, sy, sz, dx, dy, dz),
+                    ApiRequest::FieldOfView{world, x, y, z, radius}
+                        => self.handle_field_of_view(world, x, y, z, radius).await,
 
                     ApiRequest::RenderArea {world, left, top, right, bottom, color, points}
                         => self.handle_render_area(world, left, top, right, bottom, color, &points).await,
+
+                    ApiRequest::ExportWalkability {world, left, top, right, bottom}
+                        => self.handle_export_walkability(world, left, top, right, bottom).await,
+                    ApiRequest::StepCacheStats {world}
+                        => self.handle_step_cache_stats(world),
+
+                    ApiRequest::ExportNavMesh {world, left, top, right, bottom, flags_walk, flags_ignore}
+                        => self.handle_export_navmesh(world, left, top, right, bottom, flags_walk, flags_ignore).await,
+
+                    ApiRequest::ResolveZ {world, x, y, z}
+                        => self.handle_resolve_z(world, x, y, z),
+                    ApiRequest::TileInfo {world, x, y}
+                        => self.handle_tile_info(world, x, y),
+
+                    ApiRequest::TileDataGet {land, id}
+                        => self.handle_tiledata_get(land, id),
+                    ApiRequest::TileDataFind {name, land}
+                        => self.handle_tiledata_find(&name, land),
                 }
             },
 
@@ -281,7 +545,14 @@ impl ApiHandler {
             ApiResponse::Success { .. } |
             ApiResponse::Error { .. } |
             ApiResponse::QueryReply { .. } |
-            ApiResponse::TraceReply { .. } => {
+            ApiResponse::TraceReply { .. } |
+            ApiResponse::TourReply { .. } |
+            ApiResponse::LineOfSightReply { .. } |
+            ApiResponse::NavMeshReply { .. } |
+            ApiResponse::StepCacheReply { .. } |
+            ApiResponse::ResolveZReply { .. } |
+            ApiResponse::TileInfoReply { .. } |
+            ApiResponse::TileDataReply { .. } => {
                 let response_body = serde_json::to_string(&response).unwrap();
 
                 Ok(Response::builder()
@@ -302,6 +573,15 @@ impl ApiHandler {
                        .body(Body::from(image_buffer))
                        .unwrap())
             }
+
+            // binary data, such as exported maps
+            ApiResponse::BinaryReply { data } => {
+                Ok(Response::builder()
+                       .header("Content-Type", "application/octet-stream")
+                       .header("Content-Length", data.len())
+                       .body(Body::from(data.clone()))
+                       .unwrap())
+            }
         }
     }
 
@@ -343,10 +623,18 @@ impl ApiHandler {
         let since_epoch = start.duration_since(UNIX_EPOCH).expect("Failed to get current time");
         let current_time = since_epoch.as_secs();
 
+        let mut skipped = 0;
         for &Item{ world, serial, graphic, x, y, z, .. } in items {
-            self.world_model.insert_item(TopLevelItem::new(world, x, y, z, serial, graphic, current_time));
+            if !self.world_model.insert_item(TopLevelItem::new(world, x, y, z, serial, graphic, current_time)) {
+                warn!("item {serial} is skipped, world {world} is not loaded");
+                skipped += 1;
+            }
+        }
+
+        match skipped {
+            0 => ApiResponse::Success {},
+            _ => ApiResponse::Error { err: format!("{skipped} items are skipped, their worlds are not loaded") },
         }
-        ApiResponse::Success {}
     }
 
     fn handle_multi_items_add(&self, items: &Vec<MultiItem>) -> ApiResponse {
@@ -356,50 +644,342 @@ impl ApiHandler {
         let since_epoch = start.duration_since(UNIX_EPOCH).expect("Failed to get current time");
         let current_time = since_epoch.as_secs();
 
+        let mut skipped = 0;
         for MultiItem { item, parts } in items {
             let &Item {world, x, y, z, serial, graphic, ..} = item;
-            self.world_model.insert_multi_item(TopLevelItem::new(world, x, y, z, serial, graphic, current_time), parts);
+            if !self.world_model.insert_multi_item(TopLevelItem::new(world, x, y, z, serial, graphic, current_time), parts) {
+                warn!("multi-object {serial} is skipped, world {world} is not loaded");
+                skipped += 1;
+            }
+        }
+
+        match skipped {
+            0 => ApiResponse::Success {},
+            _ => ApiResponse::Error { err: format!("{skipped} items are skipped, their worlds are not loaded") },
         }
-        ApiResponse::Success {}
     }
 
     fn handle_query(&self, world: u8, left: isize, top: isize, right: isize, bottom: isize) -> ApiResponse {
         info!("Api::query world: {world}, area: {left}, {top} - {right}, {bottom}");
         let mut items = Vec::new();
-        self.world_model.query(world, left, top, right, bottom, &mut items);
+        if !self.world_model.query(world, left, top, right, bottom, &mut items) {
+            return ApiResponse::Error { err: format!("World {world} is not loaded") }
+        }
 
         ApiResponse::QueryReply { items }
     }
 
 
+    /// surveyor of the world with the extended passability flags of the request.
+    /// It borrows the model, so it is created inside of the blocking tasks with their clone of the model
+    fn surveyor_for(model: &WorldModel, world: u8, flags_walk: Option<Vec<TileFlags>>, flags_ignore: Option<Vec<TileFlags>>) -> Result<WorldSurveyor<'_>, ApiResponse> {
+        let world_model = match model.world(world) {
+            Some(world_model) => world_model,
+            None => {
+                warn!("world {world} is not loaded");
+                return Err(ApiResponse::Error { err: format!("World {world} is not loaded") })
+            }
+        };
+
+        let walkable = flags_walk.unwrap_or_default();
+        let ignore = flags_ignore.unwrap_or_default();
+        if walkable.is_empty() && ignore.is_empty() {
+            Ok(WorldSurveyor::new(world_model))
+        } else {
+            Ok(WorldSurveyor::new_with_flags(world_model, walkable, ignore))
+        }
+    }
+
+
+    /// traces of worlds which are not loaded are empty
+    fn trace_no_data() -> ApiResponse {
+        ApiResponse::TraceReply { points: Vec::new(), areas: None, stats: None, status: Some(TraceStatus::NoData) }
+    }
+
+
     async fn handle_trace_path(&self, world: u8, sx: isize, sy: isize, sz: i8, dx: isize, dy: isize, dz: i8, options: &TraceOptions) -> ApiResponse {
         info!("Api::trace_path world {world}, from {sx}, {sy}, {sz} -> to {dx}, {dy}, {dz}");
         let model = self.world_model.clone();
 
         let options = options.clone();
-        let walkable = options.flags_walk.clone().unwrap_or(vec![]);
-        let ignore = options.flags_ignore .clone().unwrap_or(vec![]);
+        let with_stats = options.stats.unwrap_or(false);
         let task = tokio::task::spawn_blocking(move || {
+            let surv = match Self::surveyor_for(&model, world, options.flags_walk.clone(), options.flags_ignore.clone()) {
+                Ok(surv) => surv,
+                Err(_) => return Self::trace_no_data(),
+            };
+
             let mut points = Vec::new();
-            let world = model.world(world).unwrap();
-            let surv = if walkable.len() == 0 && ignore.len() == 0 {
-                WorldSurveyor::new(world)
-            } else {
-                WorldSurveyor::new_with_flags(world, walkable, ignore)
+            let Trace { status, stats, areas } = surv.trace_a_star(sx, sy, sz, 0, dx, dy, dz, 0, &mut points, &options);
+            ApiResponse::TraceReply {
+                points,
+                areas: Some(areas),
+                stats: with_stats.then_some(stats),
+                status: Some(status),
+            }
+        });
+
+        task.await.unwrap()
+    }
+
+
+    #[allow(clippy::too_many_arguments)]
+    async fn handle_trace_intercept(&self, world: u8, sx: isize, sy: isize, sz: i8, target: Vec<Point>, speed: f64, options: &TraceOptions) -> ApiResponse {
+        info!("Api::trace_intercept world {world}, from {sx}, {sy}, {sz} -> target with {} positions, speed {speed}", target.len());
+        let model = self.world_model.clone();
+
+        let options = options.clone();
+        let with_stats = options.stats.unwrap_or(false);
+        let task = tokio::task::spawn_blocking(move || {
+            let surv = match Self::surveyor_for(&model, world, options.flags_walk.clone(), options.flags_ignore.clone()) {
+                Ok(surv) => surv,
+                Err(_) => return Self::trace_no_data(),
+            };
+
+            let mut points = Vec::new();
+            let Trace { status, stats, .. } = surv.trace_intercept(sx, sy, sz, 0, &target, speed, &mut points, &options);
+            ApiResponse::TraceReply {
+                points,
+                areas: None,
+                stats: with_stats.then_some(stats),
+                status: Some(status),
+            }
+        });
+
+        task.await.unwrap()
+    }
+
+
+    #[allow(clippy::too_many_arguments)]
+    async fn handle_trace_flee(&self, world: u8, sx: isize, sy: isize, sz: i8, threats: Vec<Point>, distance: isize, threat_radius: Option<isize>, options: &TraceOptions) -> ApiResponse {
+        info!("Api::trace_flee world {world}, from {sx}, {sy}, {sz} -> {distance} tiles away from {} threats", threats.len());
+        let model = self.world_model.clone();
+
+        let options = options.clone();
+        let with_stats = options.stats.unwrap_or(false);
+        let threat_radius = threat_radius.unwrap_or(distance);
+        let task = tokio::task::spawn_blocking(move || {
+            let surv = match Self::surveyor_for(&model, world, options.flags_walk.clone(), options.flags_ignore.clone()) {
+                Ok(surv) => surv,
+                Err(_) => return Self::trace_no_data(),
             };
 
-            surv.trace_a_star(sx, sy, sz, 0, dx, dy, dz, 0, &mut points, &options);
-            points
+            let mut points = Vec::new();
+            let Trace { status, stats, .. } = surv.trace_flee(sx, sy, sz, 0, &threats, distance, threat_radius, &mut points, &options);
+            ApiResponse::TraceReply {
+                points,
+                areas: None,
+                stats: with_stats.then_some(stats),
+                status: Some(status),
+            }
         });
 
-        let points = task.await.unwrap();
-        ApiResponse::TraceReply { points }
+        task.await.unwrap()
+    }
+
+
+    #[allow(clippy::too_many_arguments)]
+    async fn handle_trace_tour(&self, world: u8, sx: isize, sy: isize, sz: i8, waypoints: Vec<Point>, closed: Option<bool>, options: &TraceOptions) -> ApiResponse {
+        info!("Api::trace_tour world {world}, from {sx}, {sy}, {sz} through {} waypoints", waypoints.len());
+        if waypoints.len() > MAX_TOUR_WAYPOINTS {
+            return ApiResponse::Error { err: format!("Too many waypoints, at most {MAX_TOUR_WAYPOINTS} are allowed") }
+        }
+
+        let model = self.world_model.clone();
+
+        let options = options.clone();
+        let closed = closed.unwrap_or(true);
+        let task = tokio::task::spawn_blocking(move || {
+            let surv = match Self::surveyor_for(&model, world, options.flags_walk.clone(), options.flags_ignore.clone()) {
+                Ok(surv) => surv,
+                Err(err) => return err,
+            };
+
+            let Tour { order, unreachable, broken, cost, points } = surv.trace_tour(sx, sy, sz, &waypoints, closed, &options);
+            ApiResponse::TourReply { order, unreachable, broken, cost, points }
+        });
+
+        task.await.unwrap()
+    }
+
+
+    #[allow(clippy::too_many_arguments)]
+    fn handle_line_of_sight(&self, world: u8, sx: isize, sy: isize, sz: i8, dx: isize, dy: isize, dz: i8) -> ApiResponse {
+        info!("Api::line_of_sight world {world}, from {sx}, {sy}, {sz} -> to {dx}, {dy}, {dz}");
+        let world = match self.world_model.world(world) {
+            Some(world) => world,
+            None => {
+                warn!("world {world} is not loaded");
+                return ApiResponse::Error { err: format!("World {world} is not loaded") }
+            }
+        };
+        let surveyor = WorldSurveyor::new(world);
+
+        let blocker = surveyor.line_of_sight(sx, sy, sz, dx, dy, dz);
+        ApiResponse::LineOfSightReply { visible: blocker.is_none(), blocker }
+    }
+
+
+    fn handle_resolve_z(&self, world: u8, x: isize, y: isize, z: Option<i8>) -> ApiResponse {
+        info!("Api::resolve_z world {world}, at {x}, {y}, {z:?}");
+        let world = match self.world_model.world(world) {
+            Some(world) => world,
+            None => {
+                warn!("world {world} is not loaded");
+                return ApiResponse::Error { err: format!("World {world} is not loaded") }
+            }
+        };
+        let surveyor = WorldSurveyor::new(world);
+
+        let mut levels = Vec::new();
+        surveyor.standable_z_levels(x, y, &mut levels);
+        let nearest = z.and_then(|z| surveyor.nearest_standable_z(x, y, z));
+
+        ApiResponse::ResolveZReply { levels, nearest }
+    }
+
+
+    fn handle_tile_info(&self, world: u8, x: isize, y: isize) -> ApiResponse {
+        info!("Api::tile_info world {world}, at {x}, {y}");
+        let world = match self.world_model.world(world) {
+            Some(world) => world,
+            None => {
+                warn!("world {world} is not loaded");
+                return ApiResponse::Error { err: format!("World {world} is not loaded") }
+            }
+        };
+        let tiledata = &world.data.tiledata;
+
+        let mut stack = Vec::with_capacity(16);
+        world.query_tile_sources(x, y, &mut stack);
+
+        let tiles = stack.iter().map(|SourcedTile { source, serial, tile }| {
+            let (shape, passable, z_stand) = match tile.shape {
+                TileShape::Slope { z_stand, passable, .. } => (TileShapeKind::Slope, passable, passable.then_some(z_stand)),
+                TileShape::Surface { z_stand, passable, .. } => (TileShapeKind::Surface, passable, passable.then_some(z_stand)),
+                TileShape::HoverOver { z_base } => (TileShapeKind::HoverOver, false, Some(z_base)),
+                TileShape::Background { .. } => (TileShapeKind::Background, false, None),
+            };
+
+            let name = match tile.tile {
+                TileType::MapTile(num) => tiledata.get_land_tile(num).name.clone(),
+                TileType::ObjectTile(num) => tiledata.get_static_tile(num).name.clone(),
+            };
+            let flags = world.world_tile_flag(tile);
+
+            TileInfoEntry {
+                tile: tile.tile.num(),
+                source: *source,
+                serial: *serial,
+                z_base: tile.z_base(),
+                z_stand,
+                z_top: tile.z_top(),
+                shape,
+                passable,
+                flags,
+                flag_names: MulTileFlags::names(flags).into_iter().map(String::from).collect(),
+                name,
+            }
+        }).collect();
+
+        ApiResponse::TileInfoReply { tiles }
+    }
+
+
+    fn handle_tiledata_get(&self, land: bool, id: u16) -> ApiResponse {
+        info!("Api::tiledata_get land {land}, id {id:#06X}");
+        let tiledata = &self.world_model.data.tiledata;
+
+        let tile = if land {
+            tiledata.land_tiles.get(id as usize).map(|tile| TileDataEntry::land(id, tile))
+        } else {
+            tiledata.static_tiles.get(id as usize).map(|tile| TileDataEntry::object(id, tile))
+        };
+
+        match tile {
+            Some(tile) => ApiResponse::TileDataReply { tiles: vec![tile] },
+            None => ApiResponse::Error { err: format!("There is no tile {id:#06X} in tiledata") },
+        }
+    }
+
+
+    fn handle_tiledata_find(&self, name: &str, land: Option<bool>) -> ApiResponse {
+        info!("Api::tiledata_find name {name}, land {land:?}");
+        let tiledata = &self.world_model.data.tiledata;
+        let mut tiles = Vec::new();
+
+        if land != Some(false) {
+            for id in tiledata.find_land_tiles(name) {
+                tiles.push(TileDataEntry::land(id, tiledata.get_land_tile(id)));
+            }
+        }
+
+        if land != Some(true) {
+            for id in tiledata.find_static_tiles(name) {
+                tiles.push(TileDataEntry::object(id, tiledata.get_static_tile(id)));
+            }
+        }
+
+        ApiResponse::TileDataReply { tiles }
+    }
+
+
+    fn handle_step_cache_stats(&self, world: u8) -> ApiResponse {
+        info!("Api::step_cache_stats world {world}");
+        let stats = match self.world_model.world(world) {
+            Some(world) => world.step_cache.stats(),
+            None => {
+                warn!("world {world} is not loaded");
+                return ApiResponse::Error { err: format!("World {world} is not loaded") }
+            }
+        };
+
+        ApiResponse::StepCacheReply {
+            hits: stats.hits,
+            misses: stats.misses,
+            evictions: stats.evictions,
+            blocks: stats.blocks,
+            entries: stats.entries,
+        }
+    }
+
+
+    async fn handle_field_of_view(&self, world: u8, x: isize, y: isize, z: i8, radius: Option<isize>) -> ApiResponse {
+        let radius = radius.unwrap_or(18).clamp(0, MAX_FOV_RADIUS);
+        info!("Api::field_of_view world {world}, from {x}, {y}, {z} with radius {radius}");
+        let model = self.world_model.clone();
+
+        let task = tokio::task::spawn_blocking(move || {
+            let mut points = Vec::new();
+            let world = match model.world(world) {
+                Some(world) => world,
+                None => {
+                    warn!("world {world} is not loaded");
+                    return (points, false)
+                }
+            };
+
+            let surveyor = WorldSurveyor::new(world);
+            surveyor.field_of_view(x, y, z, radius, &mut points);
+            (points, true)
+        });
+
+        match task.await.unwrap() {
+            (points, true) => ApiResponse::TraceReply { points, areas: None, stats: None, status: None },
+            (points, false) => ApiResponse::TraceReply { points, areas: None, stats: None, status: Some(TraceStatus::NoData) },
+        }
     }
 
 
     async fn handle_render_area(&self, world: u8, left: Option<isize>, top: Option<isize>, right: Option<isize>, bottom: Option<isize>, color: Option<isize>, points: &Vec<Point>) -> ApiResponse {
         // TODO do rendering in a separate thread, as well as path calculation
-        let curr_world = self.world_model.world(world).unwrap();
+        let curr_world = match self.world_model.world(world) {
+            Some(world) => world,
+            None => {
+                warn!("world {world} is not loaded");
+                return ApiResponse::Error { err: format!("World {world} is not loaded") }
+            }
+        };
 
         let (bound_left, bound_top, bound_right, bound_bottom) = if points.len() > 0 {
             let mut left = isize::MAX;
@@ -476,6 +1056,58 @@ impl ApiHandler {
 
         ApiResponse::RenderReply { image }
     }
+
+
+    async fn handle_export_walkability(&self, world: u8, left: Option<isize>, top: Option<isize>, right: Option<isize>, bottom: Option<isize>) -> ApiResponse {
+        let model = self.world_model.clone();
+
+        let task = tokio::task::spawn_blocking(move || {
+            let curr_world = match model.world(world) {
+                Some(world) => world,
+                None => {
+                    warn!("world {world} is not loaded");
+                    return None
+                }
+            };
+
+            let left = left.unwrap_or(0);
+            let top = top.unwrap_or(0);
+            let right = right.unwrap_or(curr_world.base.width() as isize);
+            let bottom = bottom.unwrap_or(curr_world.base.height() as isize);
+            info!("Api::export_walkability world {world}, area: {left}, {top} - {right}, {bottom}");
+
+            let surveyor = WorldSurveyor::new(curr_world);
+            let mut data = Vec::new();
+            Some(export_walkability(&surveyor, world, left, top, right, bottom, &mut data).map(|_| data))
+        });
+
+        match task.await.unwrap() {
+            Some(Ok(data)) => ApiResponse::BinaryReply { data },
+            Some(Err(err)) => ApiResponse::Error { err: format!("Failed to export walkability map: {err}") },
+            None => ApiResponse::Error { err: format!("World {world} is not loaded") },
+        }
+    }
+
+
+    #[allow(clippy::too_many_arguments)]
+    async fn handle_export_navmesh(&self, world: u8, left: isize, top: isize, right: isize, bottom: isize,
+                                   flags_walk: Option<Vec<TileFlags>>, flags_ignore: Option<Vec<TileFlags>>) -> ApiResponse {
+        info!("Api::export_navmesh world {world}, area: {left}, {top} - {right}, {bottom}");
+        let model = self.world_model.clone();
+
+        let task = tokio::task::spawn_blocking(move || {
+            let surv = match Self::surveyor_for(&model, world, flags_walk, flags_ignore) {
+                Ok(surv) => surv,
+                Err(err) => return err,
+            };
+
+            let mut nodes = Vec::new();
+            surv.model.navmesh.nodes_in_area(&surv, left, top, right, bottom, &mut nodes);
+            ApiResponse::NavMeshReply { nodes }
+        });
+
+        task.await.unwrap()
+    }
 }
 
 
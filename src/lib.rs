@@ -5,7 +5,7 @@ use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use lazy_static::lazy_static;
 use std::sync::Mutex;
-use log::{debug, info, warn};
+use log::{debug, error, info, warn};
 
 use crate::world::{WorldModel, WorldTile};
 
@@ -14,7 +14,7 @@ mod http;
 mod world;
 
 use mul::*;
-use world::world_model::WorldData;
+use world::world_model::{FacetSpec, WorldData};
 use http::server::ServerControl;
 
 
@@ -23,7 +23,7 @@ lazy_static! {
 }
 
 
-fn _start_path_server(data_path: &Path, ui_file: PathBuf, http_address: String, http_port: u16) -> bool {
+fn _start_path_server(data_path: &Path, ui_file: PathBuf, http_address: String, http_port: u16, map_patches: bool) -> bool {
     info!("try start path server");
     {
         let mut control = SERVER_CONTROL.lock().unwrap();
@@ -32,7 +32,30 @@ fn _start_path_server(data_path: &Path, ui_file: PathBuf, http_address: String,
             return false;
         }
 
-        let world_model = Arc::new(WorldModel::new(data_path));
+        // the library has no command line, custom facets are read from facets.json in the data folder
+        let mut facets = FacetSpec::defaults();
+        let facets_path = data_path.join("facets.json");
+        if facets_path.exists() {
+            match FacetSpec::read_config(&facets_path) {
+                Ok(specs) => FacetSpec::merge(&mut facets, specs),
+                Err(err) => warn!("facets from {} are ignored: {err}", facets_path.display()),
+            }
+        }
+
+        let mut world_model = match WorldModel::new(data_path, &facets) {
+            Ok(world_model) => world_model,
+            Err(err) => {
+                error!("failed to load data files: {err}");
+                return false;
+            }
+        };
+        if map_patches {
+            world_model.apply_patches(data_path);
+        }
+
+        let world_model = Arc::new(world_model);
+        // tables built by the server executable are used if they are up to date
+        world_model.load_step_tables(data_path, false);
 
         *control = http::server::run_service(world_model, ui_file, http_address, http_port);
         debug!("path_server started");
@@ -44,7 +67,7 @@ fn _start_path_server(data_path: &Path, ui_file: PathBuf, http_address: String,
 
 #[no_mangle]
 pub extern "C" fn start_path_server() -> bool {
-    _start_path_server(&Path::new("."), PathBuf::from("www/ui.html"), "127.0.0.1".to_string(), 3000)
+    _start_path_server(Path::new("."), PathBuf::from("www/ui.html"), "127.0.0.1".to_string(), 3000, false)
 }
 
 
@@ -54,10 +77,28 @@ pub extern "C" fn start_path_server_ex(data_path: *const c_char, ui_file: *const
     let ui_file = unsafe { CStr::from_ptr(ui_file) }.to_str().unwrap();
     let http_address = unsafe { CStr::from_ptr(http_address) }.to_str().unwrap();
 
-    _start_path_server(&Path::new(data_path), PathBuf::from(ui_file), http_address.to_string(), http_port)
+    _start_path_server(Path::new(data_path), PathBuf::from(ui_file), http_address.to_string(), http_port, false)
 }
 
 
+/// the same as `start_path_server_ex`, but also allows to apply mapdif and stadif patches
+#[no_mangle]
+pub extern "C" fn start_path_server_ex2(data_path: *const c_char, ui_file: *const c_char, http_address: *const c_char, http_port: u16, map_patches: bool) -> bool {
+    let data_path =  unsafe { CStr::from_ptr(data_path) }.to_str().unwrap();
+    let ui_file = unsafe { CStr::from_ptr(ui_file) }.to_str().unwrap();
+    let http_address = unsafe { CStr::from_ptr(http_address) }.to_str().unwrap();
+
+    _start_path_server(Path::new(data_path), PathBuf::from(ui_file), http_address.to_string(), http_port, map_patches)
+}
+
+
+/// turns the verification of the checksums of UOP entries on or off, it must be called before the server is started.
+/// The checksums are verified by default
+#[no_mangle]
+pub extern "C" fn set_uop_checksums(verify: bool) {
+    uop::set_verify_checksums(verify);
+}
+
 
 #[no_mangle]
 pub extern "C" fn stop_path_server() -> bool {
@@ -1,23 +1,27 @@
 use std;
 use std::collections::{BTreeSet, HashMap};
 use std::fs::File;
+use std::io::BufWriter;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::{Instant};
 use log::{error, info, LevelFilter};
 
-use crate::world::{WorldModel, WorldTile};
+use crate::world::{WorldModel, WorldSurveyor, WorldTile};
+use crate::world::world_model::FacetSpec;
+use crate::world::export::export_walkability;
 
 use mul::*;
 use world::world_model::WorldData;
 use crate::ui::viewer::run_app;
 
 use clap;
-use clap::{arg, ArgAction, command};
+use clap::{arg, ArgAction, command, Command};
 use clap::parser::ValueSource;
 use simplelog::{ColorChoice, CombinedLogger, TerminalMode, TermLogger, WriteLogger, Config};
 
 mod ui;
+mod check;
 mod mul;
 mod http;
 mod world;
@@ -39,12 +43,21 @@ fn initialize_logging(loglevel: LevelFilter, quiet: bool, logfile: Option<&Strin
 }
 
 
-fn parse_cmd_args() -> (PathBuf, PathBuf, String, u16, bool) {
+/// what to do after the command line is parsed
+enum Task {
+    Serve { ui_file: PathBuf, http_address: String, http_port: u16, nogui: bool, step_tables: bool, map_patches: bool },
+    ExportWalkability { world: u8, output: PathBuf, map_patches: bool },
+    Check,
+}
+
+
+fn parse_cmd_args() -> (PathBuf, Vec<FacetSpec>, Task) {
     let matches = command!()
         .next_line_help(true)
         .arg(
             arg!(--loglevel <LEVEL>)
                 .required(false)
+                .global(true)
                 .default_value("debug")
                 .value_parser(["trace", "debug", "info", "warn", "error", "off" ])
                 .action(ArgAction::Set)
@@ -52,6 +65,7 @@ fn parse_cmd_args() -> (PathBuf, PathBuf, String, u16, bool) {
         .arg(
             arg!(--logfile [FILE_NAME])
                 .required(false)
+                .global(true)
                 .default_value("path_server.log")
                 .action(ArgAction::Set)
                 .help("Enables logging to a file. Disabled by default")
@@ -59,12 +73,14 @@ fn parse_cmd_args() -> (PathBuf, PathBuf, String, u16, bool) {
         .arg(
             arg!(-q --quiet)
                 .required(false)
+                .global(true)
                 .action(ArgAction::SetTrue)
                 .help("Disables output to the terminal")
         )
         .arg(
             arg!(--data)
                 .required(false)
+                .global(true)
                 .default_value(".")
                 .action(ArgAction::Set)
                 .help("Specifies the directory with Ultima Online data files.")
@@ -96,6 +112,63 @@ fn parse_cmd_args() -> (PathBuf, PathBuf, String, u16, bool) {
                 .action(ArgAction::SetTrue)
                 .help("Do not show world browser window")
         )
+        .arg(
+            arg!(--"step-tables")
+                .required(false)
+                .action(ArgAction::SetTrue)
+                .help("Use precomputed step tables, builds them next to the data files if they are missing or outdated")
+        )
+        .arg(
+            arg!(--"map-patches")
+                .required(false)
+                .global(true)
+                .action(ArgAction::SetTrue)
+                .help("Applies mapdif and stadif patches on top of the map and statics")
+        )
+        .arg(
+            arg!(--"skip-uop-checksums")
+                .required(false)
+                .global(true)
+                .action(ArgAction::SetTrue)
+                .help("Reads UOP files without verifying the checksums of their entries, for files written by tools with wrong checksums")
+        )
+        .arg(
+            arg!(--facets <FILE_NAME>)
+                .required(false)
+                .global(true)
+                .action(ArgAction::Set)
+                .help("Reads facets from a JSON file, they replace the default facets with the same index")
+        )
+        .arg(
+            arg!(--facet <SPEC>)
+                .required(false)
+                .global(true)
+                .value_parser(|value: &str| value.parse::<FacetSpec>())
+                .action(ArgAction::Append)
+                .help("Adds or replaces a facet, world:width:height[:mul|uop|auto[:N[:wrap]]], sizes are in blocks, N is the number in file names")
+        )
+        .subcommand(
+            Command::new("export-walkability")
+                .about("Exports the walkability map of the world to a file, without starting the server")
+                .arg(
+                    arg!(-w --world <N>)
+                        .required(false)
+                        .default_value("0")
+                        .value_parser(clap::value_parser!(u8))
+                        .action(ArgAction::Set)
+                        .help("Sets the world to export.")
+                )
+                .arg(
+                    arg!(-o --output <FILE_NAME>)
+                        .required(true)
+                        .action(ArgAction::Set)
+                        .help("Sets the output file name.")
+                )
+        )
+        .subcommand(
+            Command::new("check")
+                .about("Checks the data files and reports their formats and found problems, without starting the server")
+        )
         .get_matches();
 
 
@@ -137,16 +210,62 @@ fn parse_cmd_args() -> (PathBuf, PathBuf, String, u16, bool) {
     let ui_file = PathBuf::from(matches.get_one::<String>("ui").unwrap());
     let address = matches.get_one::<String>("address").unwrap().to_string();
     let nogui = matches.get_flag("nogui");
+    let step_tables = matches.get_flag("step-tables");
+    let map_patches = matches.get_flag("map-patches");
+    uop::set_verify_checksums(!matches.get_flag("skip-uop-checksums"));
+
+    // facets from the file replace the default ones, facets from the command line replace both
+    let mut facets = FacetSpec::defaults();
+    if let Some(file_name) = matches.get_one::<String>("facets") {
+        match FacetSpec::read_config(Path::new(file_name)) {
+            Ok(specs) => FacetSpec::merge(&mut facets, specs),
+            Err(err) => {
+                error!("failed to read facets from {file_name}: {err}");
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(specs) = matches.get_many::<FacetSpec>("facet") {
+        FacetSpec::merge(&mut facets, specs.cloned().collect());
+    }
+
+    let task = match matches.subcommand() {
+        Some(("export-walkability", sub_matches)) => Task::ExportWalkability {
+            world: *sub_matches.get_one::<u8>("world").unwrap(),
+            output: PathBuf::from(sub_matches.get_one::<String>("output").unwrap()),
+            map_patches,
+        },
+
+        Some(("check", _)) => Task::Check,
 
-    (mul_dir, ui_file, address, port, nogui)
+        _ => Task::Serve { ui_file, http_address: address, http_port: port, nogui, step_tables, map_patches },
+    };
+
+    (mul_dir, facets, task)
 }
 
 
-fn start(data_path: &Path, ui_file: PathBuf, http_address: String, http_port: u16, nogui: bool) {
+#[allow(clippy::too_many_arguments)]
+fn start(data_path: &Path, facets: &[FacetSpec], ui_file: PathBuf, http_address: String, http_port: u16, nogui: bool, step_tables: bool, map_patches: bool) {
     let start = Instant::now();
 
     info!("loading data from files, creating the world...");
-    let world_model = Arc::new(WorldModel::new(&data_path));
+    let mut world_model = match WorldModel::new(data_path, facets) {
+        Ok(world_model) => world_model,
+        Err(err) => {
+            error!("failed to load data files: {err}");
+            return
+        }
+    };
+    if map_patches {
+        world_model.apply_patches(data_path);
+    }
+
+    let world_model = Arc::new(world_model);
+    if step_tables {
+        world_model.load_step_tables(data_path, true);
+    }
     info!("the creation completed in {:?}", start.elapsed());
 
     let control = http::server::run_service(world_model.clone(), ui_file, http_address, http_port);
@@ -173,8 +292,60 @@ fn start(data_path: &Path, ui_file: PathBuf, http_address: String, http_port: u1
 }
 
 
+fn export(data_path: &Path, facets: &[FacetSpec], world: u8, output: &Path, map_patches: bool) {
+    let start = Instant::now();
+
+    info!("loading data from files, creating the world...");
+    let mut world_model = match WorldModel::new(data_path, facets) {
+        Ok(world_model) => world_model,
+        Err(err) => {
+            error!("failed to load data files: {err}");
+            return
+        }
+    };
+    if map_patches {
+        world_model.apply_patches(data_path);
+    }
+
+    let curr_world = match world_model.world(world) {
+        Some(curr_world) => curr_world,
+        None => {
+            error!("world {world} is not loaded");
+            return
+        }
+    };
+
+    info!("exporting walkability map of world {world} to {}", output.display());
+    let surveyor = WorldSurveyor::new(curr_world);
+    let (width, height) = (curr_world.base.width() as isize, curr_world.base.height() as isize);
+
+    let result = File::create(output)
+        .and_then(|file| export_walkability(&surveyor, world, 0, 0, width, height, &mut BufWriter::new(file)));
+
+    match result {
+        Ok(_) => info!("the export completed in {:?}", start.elapsed()),
+        Err(err) => error!("failed to export walkability map: {err}"),
+    }
+}
+
+
+fn check(data_path: &Path, facets: &[FacetSpec]) {
+    let report = check::check_data_files(data_path, facets);
+    if report.errors != 0 {
+        std::process::exit(1);
+    }
+}
+
+
 fn main() {
-    let (data_path, ui_file, http_address, http_port, nogui) = parse_cmd_args();
-    start(&data_path, ui_file, http_address, http_port, nogui);
+    let (data_path, facets, task) = parse_cmd_args();
+
+    match task {
+        Task::Serve { ui_file, http_address, http_port, nogui, step_tables, map_patches }
+            => start(&data_path, &facets, ui_file, http_address, http_port, nogui, step_tables, map_patches),
+        Task::ExportWalkability { world, output, map_patches }
+            => export(&data_path, &facets, world, &output, map_patches),
+        Task::Check => check(&data_path, &facets),
+    }
 }
 
@@ -2,17 +2,22 @@ use crate::mul;
 use mul::mulreader::*;
 use crate::mul::{LOOKUP_IDX_RECORD_SIZE, MulLookupIndexRecord};
 
+use std::collections::HashMap;
 use std::fs::File;
-use std::io::{BufReader, Error, ErrorKind, Seek, SeekFrom};
+use std::io::{BufReader, Cursor, Error, ErrorKind, Read, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
 use crate::mul::colordata::ColorData;
+use crate::mul::uop::{get_legacy_uop_path, UopFile};
+use crate::mul::verdata::Verdata;
 
+#[allow(dead_code)]    // used only by the check command, which is not a part of the library
 pub struct ArtSprite {
     pub width: u16,
     pub height: u16,
     pub pixels: Vec<Option<(u8, u8, u8)>>,
 }
 
+#[allow(dead_code)]
 impl ArtSprite {
     pub fn get_pixel(&self, x: usize, y: usize) -> Option<(u8, u8, u8)> {
         self.pixels.get(y * self.width as usize + x).copied().flatten()
@@ -33,22 +38,39 @@ impl ArtSprite {
 
 
 
+#[allow(dead_code)]    // used only by the check command, which is not a part of the library
 pub struct ArtLoader {
-    data_path: PathBuf,
+    data_file: PathBuf,     // art.mul or artLegacyMUL.uop
     pub index: Vec<Option<(u64, usize)>>,   // (pos, size) like LOOKUP_IDX_RECORD_SIZE
+    uop: Option<UopFile>,   // entries of artLegacyMUL.uop, they may be compressed
+    patches: HashMap<u32, Vec<u8>>,     // sprites replaced by verdata.mul, by art index
 }
 
 
+#[allow(dead_code)]
 impl ArtLoader {
+    // id of the file in verdata.mul
+    const VERDATA_FILE: u32 = 4;
+
+    /// reads the index of artidx.mul, or of artLegacyMUL.uop if there is no artidx.mul
     pub fn read(data_path: &Path) -> Result<Self, Error> {
+        let uop_path = get_legacy_uop_path(data_path, "art");
+        if !get_file_path_ci(data_path, "artidx.mul").exists() && uop_path.exists() {
+            return Self::read_uop(&uop_path)
+        }
+
         let mut result = Self {
-            data_path: PathBuf::from(data_path),
+            data_file: get_file_path_ci(data_path, "art.mul"),
             index: vec![],
+            uop: None,
+            patches: HashMap::new(),
         };
 
-        let f = File::open(data_path.join("artidx.mul"))?;
+        let f = File::open(get_file_path_ci(data_path, "artidx.mul"))?;
         let file_len = f.metadata()?.len();
-        assert_eq!(file_len as usize % LOOKUP_IDX_RECORD_SIZE, 0);
+        if !(file_len as usize).is_multiple_of(LOOKUP_IDX_RECORD_SIZE) {
+            return Err(Error::new(ErrorKind::InvalidData, format!("size of artidx.mul is not a multiple of {LOOKUP_IDX_RECORD_SIZE}")))
+        }
         let lookup_count = file_len as usize / LOOKUP_IDX_RECORD_SIZE;
         let f = &mut BufReader::new(f);
 
@@ -77,17 +99,71 @@ impl ArtLoader {
     }
 
 
+    /// reads the index of artLegacyMUL.uop, each sprite is a separate entry named "build/artlegacymul/{index:08}.tga",
+    /// the data of entries is the same as in art.mul
+    pub fn read_uop(path: &Path) -> Result<Self, Error> {
+        // maximum number of sprites, land tiles and then static tiles
+        const MAX_ART_COUNT: u32 = 0x14000;
+
+        let uop = UopFile::open(path)?;
+        let index = (0..MAX_ART_COUNT)
+            .map(|art_idx| uop.entry(&format!("build/artlegacymul/{art_idx:08}.tga")))
+            .map(|entry| entry.map(|entry| (entry.data_offset + entry.header_length as u64, entry.decompressed_length as usize)))
+            .collect();
+
+        Ok(Self {
+            data_file: path.to_path_buf(),
+            index,
+            uop: Some(uop),
+            patches: HashMap::new(),
+        })
+    }
+
+
+    /// replaces sprites with the patched ones from verdata, the data of a patch is the same as in art.mul.
+    /// Returns the number of replaced sprites. All patches are read before any of them is applied,
+    /// so if reading fails, nothing is replaced
+    pub fn apply_verdata(&mut self, verdata: &Verdata) -> Result<usize, Error> {
+        let patches = verdata.entries(Self::VERDATA_FILE)
+            .map(|entry| Ok((entry.block_id, verdata.read_entry(entry)?)))
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        let patched = patches.len();
+        self.patches.extend(patches);
+        Ok(patched)
+    }
+
+
     pub fn read_art(&self, art_idx: u32) -> Result<ArtSprite, Error> {
+        if let Some(data) = self.patches.get(&art_idx) {
+            return Self::decode_art(&mut Cursor::new(data), art_idx)
+        }
+
         let entry = self.index.get(art_idx as usize).copied().flatten();
         let (pos, size) = match entry {
             None => return Err(ErrorKind::InvalidData.into()),
             Some(v) => v,
         };
 
-        let f = File::open(self.data_path.join("art.mul"))?;
+        let f = File::open(&self.data_file)?;
         let f = &mut BufReader::new(f);
-        f.seek(SeekFrom::Start(pos))?;
 
+        match &self.uop {
+            Some(uop) => {
+                let entry = uop.entry(&format!("build/artlegacymul/{art_idx:08}.tga")).ok_or(Error::from(ErrorKind::InvalidData))?;
+                let data = UopFile::read_entry(f, entry)?;
+                Self::decode_art(&mut Cursor::new(data), art_idx)
+            }
+
+            None => {
+                f.seek(SeekFrom::Start(pos))?;
+                Self::decode_art(f, art_idx)
+            }
+        }
+    }
+
+    /// decodes the sprite from the current position of the reader
+    fn decode_art<R: Read + Seek>(f: &mut R, art_idx: u32) -> Result<ArtSprite, Error> {
         let _flag = mul_read_u32(f)?;   // useless, couldn't determine raw/run from it
 
         // let mut pixels = vec![];
@@ -200,4 +276,30 @@ impl ArtLoader {
 
         Ok(sprite)
     }
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::test_data::TestData;
+
+    #[test]
+    fn verdata_patches_replace_sprites() {
+        let data = TestData::new();
+        data.write("artidx.mul", &[]);
+        data.write("art.mul", &[]);
+
+        // a land tile, its pixels are stored as is
+        let sprite = vec![0; 4 + 44 * 44 * 2];
+        data.write("verdata.mul", &TestData::verdata(&[(4, 5, sprite), (30, 512, vec![0; 1188])]));
+
+        let verdata = Verdata::read(&data.path).unwrap().unwrap();
+        let mut art = ArtLoader::read(&data.path).unwrap();
+        assert!(art.read_art(5).is_err());
+
+        assert_eq!(art.apply_verdata(&verdata).unwrap(), 1);
+        let sprite = art.read_art(5).unwrap();
+        assert_eq!((sprite.width, sprite.height), (44, 44));
+    }
+}
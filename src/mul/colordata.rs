@@ -3,7 +3,7 @@ use log::trace;
 use mul::mulreader::{mul_read_u16, get_file_path_ci};
 use std::fs::File;
 use std::io::BufReader;
-use std::io::Error;
+use crate::mul::MulError;
 use std::path::Path;
 
 
@@ -30,20 +30,20 @@ const BLUE_SHIFT: usize = 0;
 
 impl ColorData {
     /// Tries to read data from a file
-    pub fn read(data_path: &Path) -> Result<Self, Error> {
+    pub fn read(data_path: &Path) -> Result<Self, MulError> {
         trace!("ColorData::read");
         let mut result = Self {
             colors: Vec::with_capacity(65536),
         };
 
         let path = get_file_path_ci(data_path, "Radarcol.mul");
-        let f = File::open(path)?;
-        let file_len = f.metadata()?.len();
+        let f = File::open(&path).map_err(|err| MulError::io(&path, err))?;
+        let file_len = f.metadata().map_err(|err| MulError::io(&path, err))?.len();
         let mut f = BufReader::new(f);
         let f = &mut f;
 
         for _ in 0..file_len / 2 {
-            let color = mul_read_u16(f)?; // 5/5/5 RGB packed to u16
+            let color = mul_read_u16(f).map_err(|err| MulError::io(&path, err))?; // 5/5/5 RGB packed to u16
             result.colors.push(Self::get_rgb(color));
         }
 
@@ -0,0 +1,45 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+
+/// error of reading a data file, always refers to the file
+#[derive(Debug)]
+pub enum MulError {
+    /// the file is missing or reading of it failed, including truncated or malformed records
+    Io { path: PathBuf, source: io::Error },
+    /// the UOP file has no chunk which must be there
+    MissingChunk { path: PathBuf, chunk: String },
+    /// contents of the file do not match any known format or version
+    Format { path: PathBuf, reason: String },
+}
+
+impl MulError {
+    pub fn io(path: &Path, source: io::Error) -> Self {
+        MulError::Io { path: path.to_path_buf(), source }
+    }
+
+    pub fn format(path: &Path, reason: impl Into<String>) -> Self {
+        MulError::Format { path: path.to_path_buf(), reason: reason.into() }
+    }
+}
+
+impl fmt::Display for MulError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MulError::Io { path, source } => write!(f, "unable to read {}: {source}", path.display()),
+            MulError::MissingChunk { path, chunk } => write!(f, "{} has no chunk {chunk}", path.display()),
+            MulError::Format { path, reason } => write!(f, "unknown format of {}: {reason}", path.display()),
+        }
+    }
+}
+
+impl error::Error for MulError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            MulError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
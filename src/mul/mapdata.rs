@@ -1,12 +1,11 @@
-use std::collections::HashMap;
 use std::io::Error;
-use std::io::SeekFrom;
-use std::io::{BufReader, Read, Seek};
+use std::io::{BufReader, Cursor, Read};
 use std::mem;
 use std::path::Path;
-use log::trace;
-use crate::mulreader::{mul_read_i8, mul_read_u16, mul_read_u32, mul_read_u64, get_world_file_path};
-use crate::uop_mapdata::{UopHeader, UopEntryHeader, UopEntry, uop_hash};
+use log::{trace, warn};
+use crate::mulreader::{mul_read_i8, mul_read_u16, mul_read_u32, mul_read_u32_list, get_file_path_ci, get_world_file_path};
+use crate::uop::UopFile;
+use crate::MulError;
 use std::fs;
 use std::fs::File;
 
@@ -25,7 +24,7 @@ struct MulMapBlock {
     cells: [[MulMapTile; 8]; 8],
 }
 
-const MUL_MAP_BLOCK_SIZE: usize = mem::size_of::<MulMapBlock>();
+pub const MUL_MAP_BLOCK_SIZE: usize = mem::size_of::<MulMapBlock>();
 
 #[derive(Debug, Copy, Clone)]
 pub struct LandTile {
@@ -50,10 +49,10 @@ impl Land {
         world: u8,
         x_blocks: usize,
         y_blocks: usize,
-    ) -> Result<Self, Error> {
+    ) -> Result<Self, MulError> {
         trace!("Land::read_mul");
         let path = get_world_file_path(data_path, "map", world, ".mul");
-        let f = &mut BufReader::new(File::open(path)?);
+        let f = &mut BufReader::new(File::open(&path).map_err(|err| MulError::io(&path, err))?);
 
 
         let mut result = Land {
@@ -62,7 +61,7 @@ impl Land {
 
         for _ in 0..x_blocks {
             for _ in 0..y_blocks {
-                result.read_block(f)?;
+                result.read_block(f).map_err(|err| MulError::io(&path, err))?;
             }
         }
 
@@ -75,45 +74,52 @@ impl Land {
         world: u8,
         x_blocks: usize,
         y_blocks: usize,
-    ) -> Result<Self, Error> {
+    ) -> Result<Self, MulError> {
         trace!("Land::read_uop");
         let path = get_world_file_path(data_path, "map", world, "LegacyMUL.uop");
-        let f = &mut BufReader::new(File::open(path)?);
+        let uop = UopFile::open(&path).map_err(|err| MulError::io(&path, err))?;
+        let f = &mut BufReader::new(File::open(&path).map_err(|err| MulError::io(&path, err))?);
 
 
         let mut result = Land {
             blocks: Vec::with_capacity(x_blocks * y_blocks),
         };
 
-        let entries = Land::read_uop_index(f)?;
         let max_block = x_blocks * y_blocks;
 
         while result.blocks.len() < max_block {
             let next_block = result.blocks.len();
             let entry_num = next_block >> 12;
             let chunk_name = format!("build/map{world}legacymul/{entry_num:08}.dat");
-            let entry_hash = uop_hash(chunk_name.as_bytes());
 
-            if entries.contains_key(&entry_hash) {
-                let entry = entries.get(&entry_hash).unwrap();
-                let data_offset = entry.data_offset + entry.header_length as u64;
-                let data_size = entry.decompressed_length as usize;
-                let blocks = data_size / MUL_MAP_BLOCK_SIZE;
-                debug_assert_eq!(
-                    data_size % MUL_MAP_BLOCK_SIZE,
-                    0,
-                    "file will not be read completely"
-                );
-
-                f.seek(SeekFrom::Start(data_offset))?;
-
-                let blocks_to_read = blocks.min(max_block - result.blocks.len());
+            match uop.entry(&chunk_name) {
+                Some(entry) => {
+                    // the chunk may be compressed, so it is read as a whole
+                    let data = UopFile::read_entry(f, entry).map_err(|err| MulError::io(&path, err))?;
+                    let blocks = data.len() / MUL_MAP_BLOCK_SIZE;
+                    debug_assert_eq!(
+                        data.len() % MUL_MAP_BLOCK_SIZE,
+                        0,
+                        "file will not be read completely"
+                    );
+
+                    let blocks_to_read = blocks.min(max_block - result.blocks.len());
+
+                    let chunk = &mut Cursor::new(data);
+                    for _ in 0..blocks_to_read {
+                        result.read_block(chunk).map_err(|err| MulError::io(&path, err))?;
+                    }
+
+                    // an empty chunk would never end the loop
+                    if blocks_to_read == 0 {
+                        return Err(MulError::format(&path, format!("chunk {chunk_name} has no blocks")))
+                    }
+                }
 
-                for _ in 0..blocks_to_read {
-                    result.read_block(f)?;
+                None => {
+                    trace!("chunk {chunk_name} for block {next_block} not found");
+                    return Err(MulError::MissingChunk { path, chunk: chunk_name })
                 }
-            } else {
-                panic!("!! chunk with hash={entry_hash} for block {next_block} not found!!");
             }
         }
 
@@ -121,8 +127,49 @@ impl Land {
         Ok(result)
     }
 
+    /// replaces blocks with the patched ones from mapdif{world}.mul, their indices are listed in mapdifl{world}.mul.
+    /// Returns the number of replaced blocks, 0 if there are no patch files
+    pub fn apply_patches(&mut self, data_path: &Path, world: u8) -> Result<usize, Error> {
+        trace!("Land::apply_patches");
+        let list_path = get_file_path_ci(data_path, &format!("mapdifl{world}.mul"));
+        if !list_path.exists() {
+            return Ok(0)
+        }
+
+        let indices = mul_read_u32_list(&list_path)?;
+        let f = &mut BufReader::new(File::open(get_file_path_ci(data_path, &format!("mapdif{world}.mul")))?);
+
+        // all blocks are read before any of them is replaced, so that a broken patch changes nothing
+        let mut patches = Vec::with_capacity(indices.len());
+        for index in indices {
+            // patched blocks follow each other in the order of the list, so each one must be read
+            let block = Self::read_block_data(f)?;
+            if (index as usize) < self.blocks.len() {
+                patches.push((index as usize, block));
+            } else {
+                warn!("map patch for world {world} has block {index} outside of the map");
+            }
+        }
+
+        for &(index, block) in &patches {
+            self.blocks[index] = block;
+        }
+
+        Ok(patches.len())
+    }
+
     #[inline]
     fn read_block<R: Read>(&mut self, reader: &mut R) -> Result<(), Error> {
+        let block = Self::read_block_data(reader)?;
+
+        // adding filled block to block list
+        self.blocks.push(block);
+
+        Ok(())
+    }
+
+    #[inline]
+    fn read_block_data<R: Read>(reader: &mut R) -> Result<LandBlock, Error> {
         let mut block: LandBlock = [[LandTile { land_tile: 0, z: 0 }; 8]; 8];
 
         let _header = mul_read_u32(reader)?; // unused header
@@ -136,36 +183,31 @@ impl Land {
             }
         }
 
-        // adding filled block to block list
-        self.blocks.push(block);
-
-        Ok(())
+        Ok(block)
     }
 
-    pub fn calc_mul_width(data_path: &Path, world: u8, y_blocks: usize) -> usize {
+    /// returns the width of the map in blocks, calculated from the size of the file
+    pub fn calc_mul_width(data_path: &Path, world: u8, y_blocks: usize) -> Result<usize, MulError> {
         let path = get_world_file_path(data_path, "map", world, ".mul");
-        let meta = fs::metadata(path).unwrap();
+        let meta = fs::metadata(&path).map_err(|err| MulError::io(&path, err))?;
         let fsize = meta.len() as usize;
 
         let blocks = fsize / MUL_MAP_BLOCK_SIZE;
 
-        blocks / y_blocks
+        Self::check_width(&path, blocks, y_blocks)
     }
 
-    pub fn calc_uop_width(data_path: &Path, world: u8, y_blocks: usize) -> usize {
+    /// returns the width of the map in blocks, calculated from the sizes of the chunks
+    pub fn calc_uop_width(data_path: &Path, world: u8, y_blocks: usize) -> Result<usize, MulError> {
         let path = get_world_file_path(data_path, "map", world, "LegacyMUL.uop");
-        let f =
-            &mut BufReader::new(File::open(path).unwrap());
-
-        let entries = Land::read_uop_index(f).unwrap();
+        let uop = UopFile::open(&path).map_err(|err| MulError::io(&path, err))?;
 
         let mut entry_num = 0;
         let mut blocks = 0;
         loop {
             let chunk_name = format!("build/map{world}legacymul/{entry_num:08}.dat");
-            let entry_hash = uop_hash(chunk_name.as_bytes());
 
-            match entries.get(&entry_hash) {
+            match uop.entry(&chunk_name) {
                 None => break,
                 Some(entry) => {
                     entry_num += 1;
@@ -175,45 +217,14 @@ impl Land {
             }
         }
 
-        blocks / y_blocks
+        Self::check_width(&path, blocks, y_blocks)
     }
 
-    fn read_uop_index<R: Read + Seek>(reader: &mut R) -> Result<HashMap<u64, UopEntry>, Error> {
-        let uop_header = UopHeader {
-            magic: mul_read_u32(reader)?,
-            version: mul_read_u32(reader)?,
-            timestamp: mul_read_u32(reader)?,
-            next_block_offset: mul_read_u64(reader)?,
-            block_size: mul_read_u32(reader)?,
-            entry_count: mul_read_u32(reader)?,
-        };
-
-        let magic = uop_header.magic;
-        assert_eq!(magic, 0x0050594D, "file signature is invalid");
-
-        reader.seek(SeekFrom::Start(uop_header.next_block_offset))?;
-        UopEntryHeader {
-            // Unused data but needs to be read
-            entry_count: mul_read_u32(reader)?,
-            next_block_offset: mul_read_u64(reader)?,
-        };
-
-        let mut entries = HashMap::new();
-        for _ in 0..uop_header.entry_count {
-            let uop_entry = UopEntry {
-                data_offset: mul_read_u64(reader)?,
-                header_length: mul_read_u32(reader)?,
-                compressed_length: mul_read_u32(reader)?,
-                decompressed_length: mul_read_u32(reader)?,
-                entry_hash: mul_read_u64(reader)?,
-                crc: mul_read_u32(reader)?,
-                is_compressed: mul_read_u16(reader)?,
-            };
-
-            entries.insert(uop_entry.entry_hash, uop_entry);
+    fn check_width(path: &Path, blocks: usize, y_blocks: usize) -> Result<usize, MulError> {
+        match blocks / y_blocks {
+            0 => Err(MulError::format(path, format!("{blocks} blocks are not enough for a map with the height of {y_blocks} blocks"))),
+            width => Ok(width),
         }
-
-        Ok(entries)
     }
 
     pub fn land_block(&self, index: usize) -> &LandBlock {
@@ -221,3 +232,45 @@ impl Land {
         &self.blocks[index]
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::test_data::{TestData, GRASS};
+
+    fn u32_list(values: &[u32]) -> Vec<u8> {
+        values.iter().flat_map(|value| value.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn patches_replace_listed_blocks() {
+        let data = TestData::new();
+        data.write_world(0, 2, 2, &[]);
+        let mut land = Land::read_mul(&data.path, 0, 2, 2).unwrap();
+        assert_eq!(land.apply_patches(&data.path, 0).unwrap(), 0);
+
+        // block 99 is outside of the map, but its data still has to be skipped
+        data.write("mapdifl0.mul", &u32_list(&[1, 99, 3]));
+        data.write("mapdif0.mul", &[TestData::land_block(5, 10), TestData::land_block(6, -5), TestData::land_block(7, 1)].concat());
+        assert_eq!(land.apply_patches(&data.path, 0).unwrap(), 2);
+
+        let tile = |index: usize| (land.land_block(index)[3][4].land_tile, land.land_block(index)[3][4].z);
+        assert_eq!(tile(0), (GRASS, 0));
+        assert_eq!(tile(1), (5, 10));
+        assert_eq!(tile(2), (GRASS, 0));
+        assert_eq!(tile(3), (7, 1));
+    }
+
+    #[test]
+    fn broken_patches_change_nothing() {
+        let data = TestData::new();
+        data.write_world(0, 2, 2, &[]);
+        let mut land = Land::read_mul(&data.path, 0, 2, 2).unwrap();
+
+        data.write("mapdifl0.mul", &u32_list(&[1, 2]));
+        data.write("mapdif0.mul", &TestData::land_block(5, 10));
+        assert!(land.apply_patches(&data.path, 0).is_err());
+        assert_eq!(land.land_block(1)[0][0].land_tile, GRASS);
+    }
+}
@@ -1,7 +1,10 @@
 pub mod mulreader;
+pub mod error;
 pub mod tiledata;
 pub mod mapdata;
 pub mod uop_mapdata;
+pub mod uop;
+pub mod verdata;
 pub mod staticdata;
 pub mod multidata;
 pub mod colordata;
@@ -21,9 +24,10 @@ struct MulLookupIndexRecord {
 #[derive(Debug, Copy, Clone)]
 pub struct MulSlice(pub usize, pub usize);
 
-const LOOKUP_IDX_RECORD_SIZE: usize = mem::size_of::<MulLookupIndexRecord>();
+pub const LOOKUP_IDX_RECORD_SIZE: usize = mem::size_of::<MulLookupIndexRecord>();
 
 
+pub use error::MulError;
 pub use tiledata::TileData;
 pub use mapdata::Land;
 pub use staticdata::Static;
@@ -65,6 +65,12 @@ pub fn mul_read_fixed_str20<R: Read>(reader: &mut R) -> Result<[u8; 20], Error>
     Ok(buff)
 }
 
+/// reads the whole file as a list of u32, e.g. block indices of patch files
+pub fn mul_read_u32_list(path: &Path) -> Result<Vec<u32>, Error> {
+    let data = fs::read(path)?;
+    Ok(data.chunks_exact(4).map(|v| u32::from_le_bytes([v[0], v[1], v[2], v[3]])).collect())
+}
+
 /// Resolves a filename relative to `dir` case-insensitively.
 /// If nothing matches, it returns the original path
 pub fn get_file_path_ci(dir: &Path, filename: &str) -> PathBuf {
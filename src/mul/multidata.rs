@@ -1,13 +1,16 @@
 use crate::mul::{MulLookupIndexRecord, LOOKUP_IDX_RECORD_SIZE};
 use crate::{mul, MulSlice};
-use log::{debug, trace};
+use log::{debug, trace, warn};
 use mul::mulreader::{mul_read_i16, mul_read_u16, mul_read_u32, get_file_path_ci};
 use std::fs::File;
 
-use std::io::BufReader;
+use std::io::{BufReader, Cursor, Read, Seek, SeekFrom};
 use std::io::Error;
 use std::mem;
 use std::path::Path;
+use crate::uop::UopFile;
+use crate::verdata::Verdata;
+use crate::MulError;
 
 #[repr(C, packed)]
 #[derive(Debug, Copy, Clone)]
@@ -47,46 +50,25 @@ pub struct Multi {
 }
 
 impl Multi {
-    pub fn read(data_path: &Path) -> Result<Self, Error> {
+    // id of the file in verdata.mul
+    const VERDATA_FILE: u32 = 14;
+
+    pub fn read(data_path: &Path) -> Result<Self, MulError> {
         trace!("Multi::read");
         let path = get_file_path_ci(data_path, "multi.mul");
-        let f = File::open(path)?;
-        let f_size = f.metadata()?.len() as usize;
-
-        let f = &mut BufReader::new(f);
-
-        // read data file with information about tiles
-        let path = get_file_path_ci(data_path, "multi.idx");
-        let fi = File::open(path)?;
-        let fi_size = fi.metadata()?.len();
-        let fi = &mut BufReader::new(fi);
-
-
-        // calculate count of index records and MultTile in files
-        let multi_idx_count = fi_size as usize / LOOKUP_IDX_RECORD_SIZE;
-
-        let mut raw_idx = Vec::new();
-        let mut pre7090 = true;
-        let mut is7090 = true;
-
-        for _ in 0..multi_idx_count {
-            let idx = MulLookupIndexRecord {
-                offset: mul_read_u32(fi)?,
-                length: mul_read_u32(fi)?,
-                unknown1: mul_read_u32(fi)?,
-            };
+        let uop_path = get_file_path_ci(data_path, "MultiCollection.uop");
+        if !path.exists() && uop_path.exists() {
+            return Self::read_uop(&uop_path).map_err(|err| MulError::io(&uop_path, err))
+        }
 
-            raw_idx.push((idx.offset, idx.length));
+        let f = File::open(&path).map_err(|err| MulError::io(&path, err))?;
+        let f_size = f.metadata().map_err(|err| MulError::io(&path, err))?.len() as usize;
 
-            if idx.offset != 0xFFFF_FFFF {
-                pre7090 &= (idx.length as usize % MULTI_PART_SIZE) == 0;
-                is7090 &= (idx.length as usize % MULTI_PART7090_SIZE) == 0;
-            }
-        }
+        let f = &mut BufReader::new(f);
 
-        if pre7090 == is7090 {
-            panic!("unable to determine version of multi.mul");
-        }
+        let index_path = get_file_path_ci(data_path, "multi.idx");
+        let (raw_idx, is7090) = Self::read_index(&index_path)?;
+        let multi_idx_count = raw_idx.len();
 
         let part_size = if is7090 {
             MULTI_PART7090_SIZE
@@ -106,6 +88,9 @@ impl Multi {
                 // convert file offset and length in bytes to index and count
                 let index = o as usize / part_size;
                 let count = l as usize / part_size;
+                if index + count > multi_tiles_count {
+                    return Err(MulError::format(&index_path, format!("multi-object at offset {o} is outside of multi.mul")))
+                }
 
                 Some(MulSlice(index, count))
             } else {
@@ -116,29 +101,166 @@ impl Multi {
         }
 
         for _ in 0..multi_tiles_count {
-            let tile = MulMultiPart {
-                static_tile: mul_read_u16(f)?,
-                x: mul_read_i16(f)?,
-                y: mul_read_i16(f)?,
-                z: mul_read_i16(f)?,
-                flags: mul_read_u32(f)?,
+            let part = Self::read_part(f, is7090).map_err(|err| MulError::io(&path, err))?;
+            result.parts.push(part);
+        }
+
+        Ok(result)
+    }
+
+    /// reads offsets and lengths of multi-objects from multi.idx, the offset 0xFFFFFFFF means a missing multi-object.
+    /// The second value is true if multi.mul has the 7090 format, it is determined by the lengths
+    pub fn read_index(index_path: &Path) -> Result<(Vec<(u32, u32)>, bool), MulError> {
+        let fi = File::open(index_path).map_err(|err| MulError::io(index_path, err))?;
+        let fi_size = fi.metadata().map_err(|err| MulError::io(index_path, err))?.len();
+        let fi = &mut BufReader::new(fi);
+
+        // calculate count of index records and MultTile in files
+        let multi_idx_count = fi_size as usize / LOOKUP_IDX_RECORD_SIZE;
+
+        let mut raw_idx = Vec::with_capacity(multi_idx_count);
+        let mut pre7090 = true;
+        let mut is7090 = true;
+
+        for _ in 0..multi_idx_count {
+            let idx = Self::read_index_record(fi).map_err(|err| MulError::io(index_path, err))?;
+
+            raw_idx.push((idx.offset, idx.length));
+
+            if idx.offset != 0xFFFF_FFFF {
+                pre7090 &= (idx.length as usize).is_multiple_of(MULTI_PART_SIZE);
+                is7090 &= (idx.length as usize).is_multiple_of(MULTI_PART7090_SIZE);
+            }
+        }
+
+        if pre7090 == is7090 {
+            return Err(MulError::format(index_path, "unable to determine version of multi.mul"))
+        }
+
+        Ok((raw_idx, is7090))
+    }
+
+    fn read_index_record<R: Read>(fi: &mut R) -> Result<MulLookupIndexRecord, Error> {
+        Ok(MulLookupIndexRecord {
+            offset: mul_read_u32(fi)?,
+            length: mul_read_u32(fi)?,
+            unknown1: mul_read_u32(fi)?,
+        })
+    }
+
+    fn read_part<R: Read>(f: &mut R, is7090: bool) -> Result<MultiPart, Error> {
+        let tile = MulMultiPart {
+            static_tile: mul_read_u16(f)?,
+            x: mul_read_i16(f)?,
+            y: mul_read_i16(f)?,
+            z: mul_read_i16(f)?,
+            flags: mul_read_u32(f)?,
+        };
+        if is7090 {
+            mul_read_u32(f)?; // unknown flag in new format
+        }
+
+        Ok(MultiPart {
+            static_tile: tile.static_tile,
+            x: tile.x,
+            y: tile.y,
+            z: tile.z,
+            flags: tile.flags,
+        })
+    }
+
+    /// reads multi-objects from MultiCollection.uop, each multi-object is a separate entry
+    /// named "build/multicollection/{id:06}.bin"
+    pub fn read_uop(path: &Path) -> Result<Self, Error> {
+        trace!("Multi::read_uop");
+        // maximum number of multi-objects, the same as in the client
+        const MAX_MULTI_COUNT: usize = 0x2200;
+
+        let uop = UopFile::open(path)?;
+        let f = &mut BufReader::new(File::open(path)?);
+
+        let mut result = Self {
+            multis: Vec::with_capacity(MAX_MULTI_COUNT),
+            parts: Vec::new(),
+        };
+
+        for id in 0..MAX_MULTI_COUNT {
+            let entry = match uop.entry(&format!("build/multicollection/{id:06}.bin")) {
+                Some(entry) => entry,
+                None => {
+                    result.multis.push(None);
+                    continue
+                }
             };
-            if is7090 {
-                mul_read_u32(f)?; // unknown flag in new format
+
+            let data = &mut Cursor::new(UopFile::read_entry(f, entry)?);
+            let _id = mul_read_u32(data)?;
+            let count = mul_read_u32(data)? as usize;
+
+            let index = result.parts.len();
+            for _ in 0..count {
+                // unlike multi.mul, flags are 16-bit and each part has a list of cliloc ids
+                let part = MultiPart {
+                    static_tile: mul_read_u16(data)?,
+                    x: mul_read_i16(data)?,
+                    y: mul_read_i16(data)?,
+                    z: mul_read_i16(data)?,
+                    flags: mul_read_u16(data)? as u32,
+                };
+
+                let clilocs = mul_read_u32(data)? as i64;
+                data.seek(SeekFrom::Current(clilocs * 4))?;
+
+                result.parts.push(part);
             }
 
-            result.parts.push(MultiPart {
-                static_tile: tile.static_tile,
-                x: tile.x,
-                y: tile.y,
-                z: tile.z,
-                flags: tile.flags,
-            });
+            result.multis.push(Some(MulSlice(index, count)));
         }
 
+        debug!("read {} multi-objects with {} parts from {}", result.multis.iter().flatten().count(), result.parts.len(), path.display());
         Ok(result)
     }
 
+    /// replaces multi-objects with the patched ones from verdata, the id of the multi-object is the block of the patch,
+    /// parts are always in the old format. Returns the number of replaced multi-objects.
+    /// All patches are read before any of them is applied, so if reading fails, nothing is replaced
+    pub fn apply_verdata(&mut self, verdata: &Verdata) -> Result<usize, Error> {
+        trace!("Multi::apply_verdata");
+        let mut patches = Vec::new();
+
+        for entry in verdata.entries(Self::VERDATA_FILE) {
+            // multi-objects are addressed by u16, larger ids can't be used and would only grow the list
+            if entry.block_id > u16::MAX as u32 {
+                warn!("verdata.mul patches multi-object {} outside of the valid ids", entry.block_id);
+                continue
+            }
+
+            let data = verdata.read_entry(entry)?;
+            let count = data.len() / MULTI_PART_SIZE;
+            let f = &mut Cursor::new(data);
+            let parts = (0..count)
+                .map(|_| Self::read_part(f, false))
+                .collect::<Result<Vec<_>, _>>()?;
+
+            patches.push((entry.block_id as usize, parts));
+        }
+
+        let patched = patches.len();
+        for (id, parts) in patches {
+            // parts of the patch are appended, the original ones are left unused
+            let index = self.parts.len();
+            let count = parts.len();
+            self.parts.extend(parts);
+
+            if id >= self.multis.len() {
+                self.multis.resize(id + 1, None);
+            }
+            self.multis[id] = Some(MulSlice(index, count));
+        }
+
+        Ok(patched)
+    }
+
     pub fn multi_parts(&self, multi_id: u16) -> &[MultiPart] {
         match self.multis[multi_id as usize] {
             None => &[],
@@ -146,3 +268,49 @@ impl Multi {
         }
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::test_data::TestData;
+
+    fn part(static_tile: u16, x: i16) -> Vec<u8> {
+        let mut data = static_tile.to_le_bytes().to_vec();
+        data.extend_from_slice(&x.to_le_bytes());
+        data.extend_from_slice(&[0; 8]);
+        data
+    }
+
+    #[test]
+    fn verdata_patches_replace_multis() {
+        let data = TestData::new();
+        let patch = [part(0x10, 1), part(0x11, 2)].concat();
+        data.write("verdata.mul", &TestData::verdata(&[(14, 3, patch), (14, 0x1_0000, part(0x12, 0))]));
+
+        let verdata = Verdata::read(&data.path).unwrap().unwrap();
+        let mut multis = Multi::read(&data.path).unwrap();
+        // the id outside of u16 is skipped
+        assert_eq!(multis.apply_verdata(&verdata).unwrap(), 1);
+
+        let parts: Vec<_> = multis.multi_parts(3).iter().map(|part| (part.static_tile, part.x)).collect();
+        assert_eq!(parts, vec![(0x10, 1), (0x11, 2)]);
+        assert_eq!(multis.multis.len(), 4);
+    }
+
+    #[test]
+    fn broken_verdata_changes_nothing() {
+        let data = TestData::new();
+        let mut file = TestData::verdata(&[(14, 0, part(0x10, 1)), (14, 3, part(0x11, 2))]);
+        file[4 + 20 + 12..4 + 20 + 16].copy_from_slice(&u32::MAX.to_le_bytes());    // length of the second entry
+        data.write("verdata.mul", &file);
+
+        let verdata = Verdata::read(&data.path).unwrap().unwrap();
+        let mut multis = Multi::read(&data.path).unwrap();
+        assert!(multis.apply_verdata(&verdata).is_err());
+
+        // the first patch is valid, but it is not applied either
+        assert_eq!(multis.multi_parts(0)[0].static_tile, 0);
+        assert_eq!(multis.parts.len(), 1);
+    }
+}
@@ -1,13 +1,15 @@
-use crate::mulreader::{mul_read_i8, mul_read_u16, mul_read_u32, mul_read_u8, get_world_file_path};
+use crate::mulreader::{mul_read_i8, mul_read_u16, mul_read_u32, mul_read_u32_list, mul_read_u8, get_file_path_ci, get_world_file_path};
 use std::fs::File;
 
 use crate::MulSlice;
-use log::trace;
-use std::io::BufReader;
+use log::{trace, warn};
+use std::io::{BufReader, Cursor, ErrorKind, Read, Seek, SeekFrom};
 use std::io::Error;
 use std::mem;
 use std::path::Path;
 use crate::mul::MulLookupIndexRecord;
+use crate::uop::{get_legacy_uop_path, read_legacy_mul};
+use crate::MulError;
 
 #[derive(Debug, Copy, Clone)]
 #[repr(C, packed)]
@@ -19,7 +21,7 @@ struct MulStaticTile {
     unknown1: u16,
 }
 
-const STATIC_TILE_SIZE: usize = mem::size_of::<MulStaticTile>();
+pub const STATIC_TILE_SIZE: usize = mem::size_of::<MulStaticTile>();
 
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub struct StaticTile {
@@ -43,16 +45,62 @@ impl Static {
         world: u8,
         x_blocks: usize,
         y_blocks: usize,
-    ) -> Result<Self, Error> {
+    ) -> Result<Self, MulError> {
         trace!("Static::read");
-        // read data file with information about tiles
         let path = get_world_file_path(data_path, "statics", world, ".mul");
-        let f = File::open(path)?;
-        let f_size = f.metadata()?.len();
+        if !path.exists() && get_legacy_uop_path(data_path, &format!("statics{world}")).exists() {
+            return Self::read_uop(data_path, world, x_blocks, y_blocks)
+        }
 
+        // read data file with information about tiles
+        let f = File::open(&path).map_err(|err| MulError::io(&path, err))?;
+        let f_size = f.metadata().map_err(|err| MulError::io(&path, err))?.len();
         let f = &mut BufReader::new(f);
 
-        assert_eq!(f_size as usize % STATIC_TILE_SIZE, 0);
+        // read index file with information about blocks
+        let index_path = get_world_file_path(data_path, "staidx", world, ".mul");
+        let fi = &mut BufReader::new(File::open(&index_path).map_err(|err| MulError::io(&index_path, err))?);
+
+        Self::check_size(&path, f_size)?;
+        Self::read_from(f, f_size, fi, x_blocks, y_blocks).map_err(|err| MulError::io(&path, err))
+    }
+
+    /// reads statics from "statics{world}LegacyMUL.uop" and "staidx{world}LegacyMUL.uop",
+    /// packed the same way as map files
+    pub fn read_uop(
+        data_path: &Path,
+        world: u8,
+        x_blocks: usize,
+        y_blocks: usize,
+    ) -> Result<Self, MulError> {
+        trace!("Static::read_uop");
+        let path = get_legacy_uop_path(data_path, &format!("statics{world}"));
+        let index_path = get_legacy_uop_path(data_path, &format!("staidx{world}"));
+        let statics = read_legacy_mul(data_path, &format!("statics{world}")).map_err(|err| MulError::io(&path, err))?;
+        let index = read_legacy_mul(data_path, &format!("staidx{world}")).map_err(|err| MulError::io(&index_path, err))?;
+        let f_size = statics.len() as u64;
+
+        Self::check_size(&path, f_size)?;
+        Self::read_from(&mut Cursor::new(statics), f_size, &mut Cursor::new(index), x_blocks, y_blocks)
+            .map_err(|err| MulError::io(&path, err))
+    }
+
+    fn check_size(path: &Path, f_size: u64) -> Result<(), MulError> {
+        if !(f_size as usize).is_multiple_of(STATIC_TILE_SIZE) {
+            return Err(MulError::format(path, format!("size {f_size} is not a multiple of {STATIC_TILE_SIZE} bytes")))
+        }
+
+        Ok(())
+    }
+
+    /// reads statics from the contents of statics and staidx files, `f_size` is the size of the statics
+    fn read_from<R: Read, I: Read>(
+        f: &mut R,
+        f_size: u64,
+        fi: &mut I,
+        x_blocks: usize,
+        y_blocks: usize,
+    ) -> Result<Self, Error> {
 
         // calculate count of tiles in file
         let tiles_count = f_size as usize / STATIC_TILE_SIZE;
@@ -64,20 +112,7 @@ impl Static {
 
         for _ in 0..tiles_count {
             // read tile by tile from file
-            let mul_tile = MulStaticTile {
-                static_tile: mul_read_u16(f)?, // graphic is number of static tile
-                x: mul_read_u8(f)?,            // x,y is offset relative block (in range 0-7)
-                y: mul_read_u8(f)?,
-                z: mul_read_i8(f)?,         // z coordinate of this tile
-                unknown1: mul_read_u16(f)?, // unknown field
-            };
-
-            let static_tile = StaticTile {
-                static_tile: mul_tile.static_tile,
-                x: mul_tile.x,
-                y: mul_tile.y,
-                z: mul_tile.z,
-            };
+            let static_tile = Self::read_tile(f)?;
             result.statics.push(static_tile);
 
             // TODO filter duped tiles. yet doesn't work because deleting tiles breaks the index
@@ -91,65 +126,138 @@ impl Static {
             // } else {
             //     // println!("duped tile {static_tile:?}");
             // }
-
-            // field `unknown1` seems not random
-            let u = mul_tile.unknown1;
-            if u != 0 {
-                // println!("{:?}, {:016b}", result.statics.last(), u);
-            }
         }
 
-        // read index file with information about blocks
-        let path = get_world_file_path(data_path, "staidx", world, ".mul");
-        let fi = &mut BufReader::new(File::open(path)?);
-
-
         // let mut i = 0;
         // let mut n = 0;
 
-        for _ in 0..x_blocks {
-            for _ in 0..y_blocks {
-                let idx = MulLookupIndexRecord {
-                    offset: mul_read_u32(fi)?,
-                    length: mul_read_u32(fi)?,
-                    unknown1: mul_read_u32(fi)?,
-                };
+        for (offset, length) in Self::read_index(fi, x_blocks * y_blocks)? {
+            let index = offset as usize / STATIC_TILE_SIZE;
+            let count = length as usize / STATIC_TILE_SIZE;
 
-                let offset = idx.offset;
-                let length = idx.length;
+            // if offset != 0xFFFF_FFFF && (n != 0 && offset != n) {
+            //     println!("Not sequential offset! {}", offset);
+            // }
 
-                let index = offset as usize / STATIC_TILE_SIZE;
-                let count = length as usize / STATIC_TILE_SIZE;
+            let block_slice = if offset != 0xFFFF_FFFF {
+                if index + count > result.statics.len() {
+                    return Err(Error::new(ErrorKind::InvalidData, format!("statics block at offset {offset} is outside of the file")))
+                }
 
-                // if offset != 0xFFFF_FFFF && (n != 0 && offset != n) {
-                //     println!("Not sequential offset! {}", offset);
-                // }
+                // order this slice by coordinates, for binary search in the future
+                result.statics[index..index + count]
+                    .sort_by_key(|tile| (tile.x, tile.y, tile.z));
 
-                let block_slice = if offset != 0xFFFF_FFFF {
-                    // order this slice by coordinates, for binary search in the future
-                    result.statics[index..index + count]
-                        .sort_by(|a, b| (a.x, a.y, a.z).cmp(&(b.x, b.y, b.z)));
+                // n = offset + length;
+                Some(MulSlice(index, count))
+            } else {
+                // miss_counter += 1;
+                None
+            };
 
-                    // n = offset + length;
-                    Some(MulSlice(index, count))
-                } else {
-                    // miss_counter += 1;
-                    None
-                };
+            result.blocks.push(block_slice);
+        }
 
-                result.blocks.push(block_slice);
+        Ok(result)
+    }
 
-                // i += 1;
-            }
+    /// reads one tile in the format of statics and stadif files
+    #[inline]
+    fn read_tile<R: Read>(f: &mut R) -> Result<StaticTile, Error> {
+        let mul_tile = MulStaticTile {
+            static_tile: mul_read_u16(f)?, // graphic is number of static tile
+            x: mul_read_u8(f)?,            // x,y is offset relative block (in range 0-7)
+            y: mul_read_u8(f)?,
+            z: mul_read_i8(f)?,         // z coordinate of this tile
+            unknown1: mul_read_u16(f)?, // unknown field, seems not random
+        };
+
+        Ok(StaticTile {
+            static_tile: mul_tile.static_tile,
+            x: mul_tile.x,
+            y: mul_tile.y,
+            z: mul_tile.z,
+        })
+    }
+
+    /// reads offsets and lengths in bytes of `blocks` blocks from staidx, in the order of block indices.
+    /// The offset 0xFFFFFFFF means that the block has no statics
+    pub fn read_index<I: Read>(fi: &mut I, blocks: usize) -> Result<Vec<(u32, u32)>, Error> {
+        let mut result = Vec::with_capacity(blocks);
+        for _ in 0..blocks {
+            let idx = MulLookupIndexRecord {
+                offset: mul_read_u32(fi)?,
+                length: mul_read_u32(fi)?,
+                unknown1: mul_read_u32(fi)?,
+            };
+
+            result.push((idx.offset, idx.length));
         }
 
         Ok(result)
     }
 
+    /// replaces statics of the blocks listed in stadifl{world}.mul with the patched ones from stadifi{world}.mul
+    /// and stadif{world}.mul, which have the same format as staidx and statics.
+    /// Returns the number of replaced blocks, 0 if there are no patch files.
+    /// The patches are read completely before they are applied, so if reading fails, nothing is replaced
+    pub fn apply_patches(&mut self, data_path: &Path, world: u8) -> Result<usize, Error> {
+        trace!("Static::apply_patches");
+        let list_path = get_file_path_ci(data_path, &format!("stadifl{world}.mul"));
+        if !list_path.exists() {
+            return Ok(0)
+        }
+
+        let indices = mul_read_u32_list(&list_path)?;
+        let fi = &mut BufReader::new(File::open(get_file_path_ci(data_path, &format!("stadifi{world}.mul")))?);
+        let f = &mut BufReader::new(File::open(get_file_path_ci(data_path, &format!("stadif{world}.mul")))?);
+
+        let mut patches = Vec::with_capacity(indices.len());
+        for index in indices {
+            let idx = MulLookupIndexRecord {
+                offset: mul_read_u32(fi)?,
+                length: mul_read_u32(fi)?,
+                unknown1: mul_read_u32(fi)?,
+            };
+
+            let index = index as usize;
+            if index >= self.blocks.len() {
+                warn!("statics patch for world {world} has block {index} outside of the map");
+                continue
+            }
+
+            let (offset, length) = (idx.offset, idx.length);
+            let tiles = if offset != 0xFFFF_FFFF {
+                f.seek(SeekFrom::Start(offset as u64))?;
+                let tiles = (0..length as usize / STATIC_TILE_SIZE)
+                    .map(|_| Self::read_tile(f))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Some(tiles)
+            } else {
+                None
+            };
+
+            patches.push((index, tiles));
+        }
+
+        let patched = patches.len();
+        for (index, tiles) in patches {
+            self.blocks[index] = tiles.map(|mut tiles| {
+                // patched tiles are appended, the replaced ones just stay unused
+                let start = self.statics.len();
+                tiles.sort_by_key(|tile| (tile.x, tile.y, tile.z));
+                self.statics.extend_from_slice(&tiles);
+                MulSlice(start, tiles.len())
+            });
+        }
+
+        Ok(patched)
+    }
+
     /// returns a slice corresponding to the whole block,
     /// if there are no static elements in the block, then an empty slice will be returned
     pub fn statics_block(&self, index: usize) -> &[StaticTile] {
-        debug_assert!(self.statics.len() > index);
+        debug_assert!(self.blocks.len() > index);
         let block_slice = &self.blocks[index];
         match block_slice {
             Some(b) => &self.statics[b.0..b.0 + b.1],
@@ -190,3 +298,71 @@ impl Static {
         &slice[left_index..(left_index + count)]
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::test_data::{TestData, FLOOR, WALL};
+
+    // x, y, z, tile
+    type Tile = (usize, usize, i8, u16);
+
+    // stadifl, stadifi and stadif files, `patches` are (block, tiles), None removes the statics
+    fn write_patches(data: &TestData, patches: &[(u32, Option<&[Tile]>)]) {
+        let (mut list, mut index, mut tiles) = (Vec::new(), Vec::new(), Vec::new());
+        for &(block, statics) in patches {
+            list.extend_from_slice(&block.to_le_bytes());
+            let (offset, length) = match statics {
+                Some(statics) => (tiles.len() as u32, (statics.len() * STATIC_TILE_SIZE) as u32),
+                None => (0xFFFF_FFFF, 0),
+            };
+
+            for value in [offset, length, 0] {
+                index.extend_from_slice(&value.to_le_bytes());
+            }
+
+            for &(x, y, z, tile) in statics.unwrap_or(&[]) {
+                tiles.extend_from_slice(&TestData::static_tile(x, y, z, tile));
+            }
+        }
+
+        data.write("stadifl0.mul", &list);
+        data.write("stadifi0.mul", &index);
+        data.write("stadif0.mul", &tiles);
+    }
+
+    fn tiles(statics: &Static, index: usize) -> Vec<(u8, u8, i8, u16)> {
+        statics.statics_block(index).iter().map(|tile| (tile.x, tile.y, tile.z, tile.static_tile)).collect()
+    }
+
+    #[test]
+    fn patches_replace_listed_blocks() {
+        let data = TestData::new();
+        // blocks are numbered by columns, 9, 1 is in the block 2
+        data.write_world(0, 2, 2, &[(1, 1, 0, WALL), (9, 1, 0, WALL)]);
+        let mut statics = Static::read(&data.path, 0, 2, 2).unwrap();
+        assert_eq!(statics.apply_patches(&data.path, 0).unwrap(), 0);
+
+        write_patches(&data, &[(0, None), (99, Some(&[(1, 1, 5, WALL)])), (3, Some(&[(12, 14, 5, WALL), (10, 9, 0, FLOOR)]))]);
+        assert_eq!(statics.apply_patches(&data.path, 0).unwrap(), 2);
+
+        assert_eq!(tiles(&statics, 0), vec![]);
+        assert_eq!(tiles(&statics, 2), vec![(1, 1, 0, WALL)]);
+        // patched tiles are sorted as the ones read from statics
+        assert_eq!(tiles(&statics, 3), vec![(2, 1, 0, FLOOR), (4, 6, 5, WALL)]);
+        assert_eq!(statics.statics_block_tile(3, 4, 6).len(), 1);
+    }
+
+    #[test]
+    fn broken_patches_change_nothing() {
+        let data = TestData::new();
+        data.write_world(0, 2, 2, &[(1, 1, 0, WALL)]);
+        let mut statics = Static::read(&data.path, 0, 2, 2).unwrap();
+
+        write_patches(&data, &[(0, None), (1, Some(&[(1, 9, 0, WALL)]))]);
+        data.write("stadif0.mul", &[0; STATIC_TILE_SIZE - 1]);
+        assert!(statics.apply_patches(&data.path, 0).is_err());
+        assert_eq!(tiles(&statics, 0), vec![(1, 1, 0, WALL)]);
+    }
+}
@@ -1,11 +1,14 @@
 use crate::mul;
 use mul::mulreader::*;
 use std::fs::File;
-use std::io::{Error, Seek};
+use std::io::{Cursor, Error, ErrorKind, Read, Seek};
 use std::io::BufReader;
 use std::mem;
 use std::path::Path;
-use log::trace;
+use log::{debug, trace};
+use crate::uop::{get_legacy_uop_path, read_legacy_mul};
+use crate::verdata::Verdata;
+use crate::MulError;
 
 /*
     mul file raw structures, full set of fields
@@ -80,10 +83,18 @@ struct MulStaticGroup7090 {
 const STATIC_TILE_GROUP_SIZE: usize = mem::size_of::<MulStaticGroup>();
 const STATIC_TILE_GROUP7090_SIZE: usize = mem::size_of::<MulStaticGroup7090>();
 
+// 512 groups of land tiles, in 7090 the first header is after the first tile, but the count is the same
+const LAND_TILES_SIZE: u64 = (512 * mem::size_of::<MulLandTileGroup>()) as u64;
+const LAND_TILES7090_SIZE: u64 = (512 * 4 + 0x4000 * mem::size_of::<MulLandTileFirst7090>()) as u64;
 
-// now the use of these flags requires a cast, usually to u32.
+// only tiledata.mul of exactly this size has the 7090 format
+const TILEDATA7090_SIZE: u64 = 3_188_736;
+
+
+// now the use of these flags requires a cast, usually to u64.
 // we need to come up with a more convenient interface or replace it with constants
-#[repr(u32)]
+// the upper 32 bits exist only in tiledata of 7.0.9.0 and later clients
+#[repr(u64)]
 #[allow(dead_code)]
 pub enum MulTileFlags {
     Background  = 0x0000_0001,
@@ -105,7 +116,7 @@ pub enum MulTileFlags {
     Internal    = 0x0001_0000,
     Foliage     = 0x0002_0000,
     PartialHue  = 0x0004_0000,
-    Unknown2    = 0x0008_0000,
+    NoHouse     = 0x0008_0000,  // houses can not be placed over it
     Map         = 0x0010_0000,
     Container   = 0x0020_0000,
     Wearable    = 0x0040_0000,
@@ -118,6 +129,43 @@ pub enum MulTileFlags {
     Door        = 0x2000_0000,
     StairBack   = 0x4000_0000,
     StairRight  = 0x8000_0000,
+    AlphaBlend  = 0x0001_0000_0000,
+    UseNewArt   = 0x0002_0000_0000,
+    ArtUsed     = 0x0004_0000_0000,
+    Unused      = 0x0008_0000_0000,  // not used by the client
+    NoShadow    = 0x0010_0000_0000,
+    PixelBleed  = 0x0020_0000_0000,
+    PlayAnimOnce = 0x0040_0000_0000,
+    NoDraw      = 0x0080_0000_0000,  // not drawn by the client, the bit is rarely set and its meaning is not certain
+    MultiMovable = 0x0100_0000_0000,
+}
+
+// names of the flags, in the order of bits
+const MUL_TILE_FLAG_NAMES: [&str; 41] = [
+    "Background", "Weapon", "Transparent", "Translucent", "Wall", "Damaging", "Impassable", "Wet",
+    "Unknown1", "Surface", "Bridge", "Generic", "Window", "NoShoot", "PrefixA", "PrefixAn",
+    "Internal", "Foliage", "PartialHue", "NoHouse", "Map", "Container", "Wearable", "LightSource",
+    "Animated", "HoverOver", "NoDiagonal", "Armor", "Roof", "Door", "StairBack", "StairRight",
+    "AlphaBlend", "UseNewArt", "ArtUsed", "Unused", "NoShadow", "PixelBleed", "PlayAnimOnce", "NoDraw",
+    "MultiMovable",
+];
+
+impl MulTileFlags {
+    /// returns the names of the flags set in `flags`, in the order of bits
+    pub fn names(flags: u64) -> Vec<&'static str> {
+        MUL_TILE_FLAG_NAMES
+            .iter()
+            .enumerate()
+            .filter(|(bit, _)| flags & (1 << bit) != 0)
+            .map(|(_, &name)| name)
+            .collect()
+    }
+}
+
+/// converts a zero-terminated name from tiledata to a string, replacing invalid characters
+fn tile_name(raw: &[u8; 20]) -> String {
+    let len = raw.iter().position(|&c| c == 0).unwrap_or(raw.len());
+    String::from_utf8_lossy(&raw[..len]).into_owned()
 }
 
 
@@ -125,12 +173,28 @@ pub enum MulTileFlags {
     public structure, refined
  */
 pub struct LandTileData {
-    pub flags: u32,
+    pub flags: u64,
+    pub texture_id: u16,
+    pub name: String,
 }
 
 pub struct StaticTileData {
-    pub flags: u32,
+    pub flags: u64,
     pub height: u8,
+    pub weight: u8,
+    pub quality: u8,
+    pub quantity: u8,
+    pub anim_id: u16,
+    pub hue: u8,
+    pub name: String,
+}
+
+/// layout of tiledata.mul, determined by the size of the file
+#[derive(Debug, Copy, Clone)]
+pub struct TileDataLayout {
+    pub is7090: bool,
+    pub static_tiles: usize,
+    pub trailing_bytes: u64,    // bytes after the last whole group of static tiles, they are ignored
 }
 
 /// TileData stores information about tiles of the map and tiles representing static objects
@@ -140,20 +204,55 @@ pub struct TileData {
 }
 
 impl TileData {
-    pub fn read(data_path: &Path) -> Result<Self, Error> {
+    // id of the file in verdata.mul
+    const VERDATA_FILE: u32 = 30;
+
+    pub fn read(data_path: &Path) -> Result<Self, MulError> {
         trace!("TileData::read");
+        let path = get_file_path_ci(data_path, "tiledata.mul");
+        let uop_path = get_legacy_uop_path(data_path, "tiledata");
+        if !path.exists() && uop_path.exists() {
+            trace!("TileData::read from UOP");
+            let data = read_legacy_mul(data_path, "tiledata").map_err(|err| MulError::io(&uop_path, err))?;
+            let file_len = data.len() as u64;
+            return Self::read_from(&mut Cursor::new(data), file_len).map_err(|err| MulError::io(&uop_path, err))
+        }
+
+        let f = File::open(&path).map_err(|err| MulError::io(&path, err))?;
+        let file_len = f.metadata().map_err(|err| MulError::io(&path, err))?.len();
+        Self::read_from(&mut BufReader::new(f), file_len).map_err(|err| MulError::io(&path, err))
+    }
+
+    /// returns the layout of tiledata.mul of the given size, None if the file is too short even for land tiles
+    pub fn layout(file_len: u64) -> Option<TileDataLayout> {
+        let is7090 = file_len == TILEDATA7090_SIZE;
+        let (land_size, group_size) = if is7090 {
+            (LAND_TILES7090_SIZE, STATIC_TILE_GROUP7090_SIZE as u64)
+        } else {
+            (LAND_TILES_SIZE, STATIC_TILE_GROUP_SIZE as u64)
+        };
+
+        let left_bytes = file_len.checked_sub(land_size)?;
+        Some(TileDataLayout {
+            is7090,
+            static_tiles: (left_bytes / group_size) as usize * 32,
+            trailing_bytes: left_bytes % group_size,
+        })
+    }
+
+    /// reads tiledata from the contents of tiledata.mul, the version is determined by `file_len`
+    fn read_from<R: Read + Seek>(f: &mut R, file_len: u64) -> Result<Self, Error> {
         let mut result = TileData {
             land_tiles: Vec::with_capacity(16384),
             static_tiles: Vec::with_capacity(16384),
         };
 
-        let path = get_file_path_ci(data_path, "tiledata.mul");
-        let f = File::open(path)?;
-        let file_len = f.metadata()?.len();
-        let f = &mut BufReader::new(f);
-
-
-        let is7090 = file_len == 3_188_736;
+        let layout = Self::layout(file_len)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("{file_len} bytes are not enough for land tiles")))?;
+        let is7090 = layout.is7090;
+        if layout.trailing_bytes != 0 {
+            debug!("tiledata has {} bytes after the last group of static tiles", layout.trailing_bytes);
+        }
 
         // the first half of the file (roughly) contains information about MulLandTile
         // 512 block of tile blocks
@@ -170,49 +269,115 @@ impl TileData {
                 let _header = mul_read_u32(f)?;   // unknown _header
             }
 
-            let tile = MulLandTile{
-                flags: if is7090 { mul_read_u64(f)? as u32} else { mul_read_u32(f)?},
-                texture_id: mul_read_u16(f)?,
-                tile_name: mul_read_fixed_str20(f)?,
-            };
-
-            result.land_tiles.push(LandTileData {flags: tile.flags});
+            result.land_tiles.push(Self::read_land_tile(f, is7090)?);
         }
 
 
         // The second half of the file contains the StaticTile data.
         // tiles also lay in blocks of 32 tile
         // but count of groups calculated from file size and size of MulStaticGroup[7090]
-        let left_bytes = file_len - f.stream_position()?;
-        let group_size = if is7090 { STATIC_TILE_GROUP7090_SIZE as u64 } else { STATIC_TILE_GROUP_SIZE as u64 };
-        let static_objects =  (left_bytes / group_size) * 32;
-
-        for i in 0..static_objects {
+        for i in 0..layout.static_tiles {
             if i & 0x1F == 0 {
                 let _header = mul_read_u32(f)?;   // unknown _header
             }
 
-            let tile = MulStaticObject {
-                flags: if is7090 { mul_read_u64(f)? as u32 } else { mul_read_u32(f)? },
-                weight: mul_read_u8(f)?,
-                quality: mul_read_u8(f)?,
-                unk1: mul_read_u16(f)?,   // unknown field
-                unk2: mul_read_u8(f)?,   // unknown field
-                quantity: mul_read_u8(f)?,
-                anim_id: mul_read_u16(f)?,
-                unk3: mul_read_u8(f)?,
-                hue: mul_read_u8(f)?,
-                unk4: mul_read_u16(f)?,
-                height: mul_read_u8(f)?,
-                tile_name: mul_read_fixed_str20(f)?,
-            };
-
-            result.static_tiles.push(StaticTileData {flags: tile.flags, height: tile.height});
+            result.static_tiles.push(Self::read_static_tile(f, is7090)?);
         }
 
         Ok(result)
     }
 
+    fn read_land_tile<R: Read>(f: &mut R, is7090: bool) -> Result<LandTileData, Error> {
+        // both formats are read into the wider structure
+        let tile = MulLandTileFirst7090 {
+            flags: if is7090 { mul_read_u64(f)? } else { mul_read_u32(f)? as u64 },
+            texture_id: mul_read_u16(f)?,
+            tile_name: mul_read_fixed_str20(f)?,
+        };
+
+        Ok(LandTileData {
+            flags: tile.flags,
+            texture_id: tile.texture_id,
+            name: tile_name(&tile.tile_name),
+        })
+    }
+
+    fn read_static_tile<R: Read>(f: &mut R, is7090: bool) -> Result<StaticTileData, Error> {
+        let tile = MulStaticObject7090 {
+            flags: if is7090 { mul_read_u64(f)? } else { mul_read_u32(f)? as u64 },
+            weight: mul_read_u8(f)?,
+            quality: mul_read_u8(f)?,
+            unk1: mul_read_u16(f)?,   // unknown field
+            unk2: mul_read_u8(f)?,   // unknown field
+            quantity: mul_read_u8(f)?,
+            anim_id: mul_read_u16(f)?,
+            unk3: mul_read_u8(f)?,
+            hue: mul_read_u8(f)?,
+            unk4: mul_read_u16(f)?,
+            height: mul_read_u8(f)?,
+            tile_name: mul_read_fixed_str20(f)?,
+        };
+
+        Ok(StaticTileData {
+            flags: tile.flags,
+            height: tile.height,
+            weight: tile.weight,
+            quality: tile.quality,
+            quantity: tile.quantity,
+            anim_id: tile.anim_id,
+            hue: tile.hue,
+            name: tile_name(&tile.tile_name),
+        })
+    }
+
+    /// replaces groups of 32 tiles with the patched ones from verdata. Groups 0..512 are land tiles,
+    /// the next ones are static tiles, patches are always in the old format.
+    /// Returns the number of replaced groups. All patches are read before any of them is applied,
+    /// so if reading fails, nothing is replaced
+    pub fn apply_verdata(&mut self, verdata: &Verdata) -> Result<usize, Error> {
+        trace!("TileData::apply_verdata");
+        const LAND_GROUPS: usize = 0x200;
+
+        enum Group {
+            Land(usize, Vec<LandTileData>),
+            Static(usize, Vec<StaticTileData>),
+        }
+
+        let mut patches = Vec::new();
+        for entry in verdata.entries(Self::VERDATA_FILE) {
+            let f = &mut Cursor::new(verdata.read_entry(entry)?);
+            let _header = mul_read_u32(f)?;   // unknown _header
+            let group = entry.block_id as usize;
+
+            patches.push(if group < LAND_GROUPS {
+                let tiles = (0..32).map(|_| Self::read_land_tile(f, false)).collect::<Result<_, _>>()?;
+                Group::Land(group * 32, tiles)
+            } else {
+                let tiles = (0..32).map(|_| Self::read_static_tile(f, false)).collect::<Result<_, _>>()?;
+                Group::Static((group - LAND_GROUPS) * 32, tiles)
+            });
+        }
+
+        let patched = patches.len();
+        for group in patches {
+            match group {
+                Group::Land(first, tiles) => {
+                    for (target, tile) in self.land_tiles.iter_mut().skip(first).zip(tiles) {
+                        *target = tile;
+                    }
+                }
+
+                Group::Static(first, tiles) => {
+                    for (target, tile) in self.static_tiles.iter_mut().skip(first).zip(tiles) {
+                        *target = tile;
+                    }
+                }
+            }
+        }
+
+        Ok(patched)
+    }
+
     pub fn get_land_tile(&self, land_tile: u16) -> &LandTileData {
         &self.land_tiles[land_tile as usize]
     }
@@ -220,4 +385,56 @@ impl TileData {
     pub fn get_static_tile(&self, static_tile: u16) -> &StaticTileData {
         &self.static_tiles[static_tile as usize]
     }
-}
\ No newline at end of file
+
+    /// returns the ids of the land tiles whose names contain `name`, case-insensitive
+    pub fn find_land_tiles(&self, name: &str) -> Vec<u16> {
+        let name = name.to_lowercase();
+        (0..self.land_tiles.len())
+            .filter(|&id| self.land_tiles[id].name.to_lowercase().contains(&name))
+            .map(|id| id as u16)
+            .collect()
+    }
+
+    /// returns the ids of the static tiles whose names contain `name`, case-insensitive
+    pub fn find_static_tiles(&self, name: &str) -> Vec<u16> {
+        let name = name.to_lowercase();
+        (0..self.static_tiles.len())
+            .filter(|&id| self.static_tiles[id].name.to_lowercase().contains(&name))
+            .map(|id| id as u16)
+            .collect()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::test_data::{TestData, WALL};
+
+    #[test]
+    fn names_of_flags_match_their_bits() {
+        let flags = MulTileFlags::NoHouse as u64 | MulTileFlags::ArtUsed as u64
+            | MulTileFlags::Unused as u64 | MulTileFlags::NoDraw as u64 | MulTileFlags::MultiMovable as u64;
+        assert_eq!(MulTileFlags::names(flags), ["NoHouse", "ArtUsed", "Unused", "NoDraw", "MultiMovable"]);
+    }
+
+    #[test]
+    fn verdata_patches_replace_groups_of_tiles() {
+        let data = TestData::new();
+
+        // the first group of static tiles, WALL becomes a surface 5 high
+        let mut group = vec![0; 4 + 32 * 37];
+        let offset = 4 + WALL as usize * 37;
+        group[offset..offset + 4].copy_from_slice(&(MulTileFlags::Surface as u32).to_le_bytes());
+        group[offset + 16] = 5;
+        data.write("verdata.mul", &TestData::verdata(&[(30, 512, group)]));
+
+        let verdata = Verdata::read(&data.path).unwrap().unwrap();
+        let mut tiledata = TileData::read(&data.path).unwrap();
+        assert_eq!(tiledata.get_static_tile(WALL).flags & MulTileFlags::Impassable as u64, MulTileFlags::Impassable as u64);
+
+        assert_eq!(tiledata.apply_verdata(&verdata).unwrap(), 1);
+        let wall = tiledata.get_static_tile(WALL);
+        assert_eq!((wall.flags, wall.height), (MulTileFlags::Surface as u64, 5));
+    }
+}
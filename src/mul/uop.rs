@@ -0,0 +1,360 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use flate2::read::ZlibDecoder;
+use log::trace;
+
+use crate::mulreader::{get_file_path_ci, mul_read_u16, mul_read_u32, mul_read_u64};
+use crate::uop_mapdata::{uop_hash, UopEntry, UopEntryHeader, UopHeader};
+
+const UOP_MAGIC: u32 = 0x0050594D;
+
+// size of an entry in the index: data_offset, header_length, compressed_length, decompressed_length, entry_hash, crc, is_compressed
+const UOP_ENTRY_SIZE: u64 = 34;
+
+// values of `UopEntry::is_compressed`
+const UOP_COMPRESSION_NONE: u16 = 0;
+const UOP_COMPRESSION_ZLIB: u16 = 1;
+const UOP_COMPRESSION_BWT: u16 = 3;    // zlib, then the Burrows-Wheeler transform, not supported
+
+// zlib can't compress better than about 1:1032, a larger decompressed length is not trusted for the allocation
+const ZLIB_MAX_RATIO: usize = 1032;
+
+static VERIFY_CHECKSUMS: AtomicBool = AtomicBool::new(true);
+
+/// turns the verification of the checksums of UOP entries on or off, it is on by default.
+/// Some tools write wrong checksums, the files they produce can be read only without the verification
+pub fn set_verify_checksums(verify: bool) {
+    VERIFY_CHECKSUMS.store(verify, Ordering::Relaxed);
+}
+
+
+/// UOP container, a set of named entries in one file. Entries are found by the hash of their names,
+/// for example "build/map0legacymul/00000000.dat", the names themselves are not stored
+pub struct UopFile {
+    path: PathBuf,
+    entries: HashMap<u64, UopEntry>,
+}
+
+impl UopFile {
+    /// reads the index of the container, the data of the entries is read on demand
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        trace!("UopFile::open {}", path.display());
+        let f = File::open(path)?;
+        let file_len = f.metadata()?.len();
+        let f = &mut BufReader::new(f);
+
+        let header = UopHeader {
+            magic: mul_read_u32(f)?,
+            version: mul_read_u32(f)?,
+            timestamp: mul_read_u32(f)?,
+            next_block_offset: mul_read_u64(f)?,
+            block_size: mul_read_u32(f)?,
+            entry_count: mul_read_u32(f)?,
+        };
+
+        let magic = header.magic;
+        if magic != UOP_MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, format!("{} is not a UOP file", path.display())))
+        }
+
+        // the index is split into blocks, each block has its own count of entries and the offset of the next block.
+        // Neither the count nor the offsets are trusted, the file can't hold more entries than its size allows,
+        // and a block outside of the file or a chain of blocks that loops is rejected
+        let max_entries = file_len / UOP_ENTRY_SIZE;
+        let mut entries = HashMap::with_capacity((header.entry_count as u64).min(max_entries) as usize);
+        let mut visited = HashSet::new();
+        let mut next_block_offset = header.next_block_offset;
+        while next_block_offset != 0 {
+            if next_block_offset >= file_len || !visited.insert(next_block_offset) {
+                return Err(Error::new(ErrorKind::InvalidData,
+                    format!("{} has a broken chain of index blocks at offset {next_block_offset}", path.display())))
+            }
+
+            f.seek(SeekFrom::Start(next_block_offset))?;
+            let block = UopEntryHeader {
+                entry_count: mul_read_u32(f)?,
+                next_block_offset: mul_read_u64(f)?,
+            };
+
+            for _ in 0..block.entry_count {
+                let entry = UopEntry {
+                    data_offset: mul_read_u64(f)?,
+                    header_length: mul_read_u32(f)?,
+                    compressed_length: mul_read_u32(f)?,
+                    decompressed_length: mul_read_u32(f)?,
+                    entry_hash: mul_read_u64(f)?,
+                    crc: mul_read_u32(f)?,
+                    is_compressed: mul_read_u16(f)?,
+                };
+
+                // unused slots of the block
+                if entry.data_offset != 0 {
+                    entries.insert(entry.entry_hash, entry);
+                }
+            }
+
+            next_block_offset = block.next_block_offset;
+        }
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            entries,
+        })
+    }
+
+    #[inline]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// returns the entry with the given name
+    pub fn entry(&self, name: &str) -> Option<&UopEntry> {
+        self.entries.get(&uop_hash(name.as_bytes()))
+    }
+
+    /// reads the data of the entry from an opened container, decompresses it if necessary.
+    /// The entry must fit into the file, its checksum and the size of decompressed data must match.
+    /// Entries compressed with zlib and bwt are rejected as unsupported
+    pub fn read_entry<R: Read + Seek>(reader: &mut R, entry: &UopEntry) -> Result<Vec<u8>, Error> {
+        let hash = entry.entry_hash;
+        let (header_length, compressed_length) = (entry.header_length as u64, entry.compressed_length as u64);
+
+        // lengths are checked before anything is allocated
+        let file_len = reader.seek(SeekFrom::End(0))?;
+        if entry.data_offset.saturating_add(header_length + compressed_length) > file_len {
+            return Err(Error::new(ErrorKind::InvalidData, format!("UOP entry {hash:016X} is outside of the file")))
+        }
+
+        let mut header = vec![0; header_length as usize];
+        let mut data = vec![0; compressed_length as usize];
+        reader.seek(SeekFrom::Start(entry.data_offset))?;
+        reader.read_exact(&mut header)?;
+        reader.read_exact(&mut data)?;
+
+        // the checksum covers the header of the entry, the metadata stored in front of the data, 0 means there is no checksum
+        let crc = entry.crc;
+        if crc != 0 && VERIFY_CHECKSUMS.load(Ordering::Relaxed) && adler32(&header) != crc {
+            return Err(Error::new(ErrorKind::InvalidData, format!("checksum of UOP entry {hash:016X} does not match")))
+        }
+
+        let data = match entry.is_compressed {
+            UOP_COMPRESSION_NONE => data,
+
+            UOP_COMPRESSION_ZLIB => {
+                // one byte more than expected is enough to reject the entry, a broken entry can't inflate without bounds
+                let capacity = (entry.decompressed_length as usize).min(data.len().saturating_mul(ZLIB_MAX_RATIO));
+                let mut result = Vec::with_capacity(capacity);
+                ZlibDecoder::new(&data[..]).take(entry.decompressed_length as u64 + 1).read_to_end(&mut result)?;
+                result
+            }
+
+            UOP_COMPRESSION_BWT => {
+                return Err(Error::new(ErrorKind::Unsupported, format!("UOP entry {hash:016X} is compressed with zlib and bwt, which is not supported")))
+            }
+
+            other => {
+                return Err(Error::new(ErrorKind::InvalidData, format!("UOP entry {hash:016X} has unknown compression {other}")))
+            }
+        };
+
+        let decompressed_length = entry.decompressed_length;
+        if data.len() != decompressed_length as usize {
+            return Err(Error::new(ErrorKind::InvalidData,
+                format!("UOP entry {hash:016X} has {} bytes of data, {decompressed_length} expected", data.len())))
+        }
+
+        Ok(data)
+    }
+
+    /// reads the contents of the original MUL file, which is split into chunks named
+    /// "{prefix}00000000.dat", "{prefix}00000001.dat" and so on, until the first missing chunk
+    pub fn read_chunks(&self, prefix: &str) -> Result<Vec<u8>, Error> {
+        let f = &mut BufReader::new(File::open(&self.path)?);
+        let mut result = Vec::new();
+
+        for chunk in 0.. {
+            match self.entry(&format!("{prefix}{chunk:08}.dat")) {
+                Some(entry) => result.extend_from_slice(&Self::read_entry(f, entry)?),
+                None => break,
+            }
+        }
+
+        Ok(result)
+    }
+}
+
+
+/// reads the contents of a MUL file packed into "{stem}LegacyMUL.uop" the same way as map files,
+/// e.g. "statics0LegacyMUL.uop" with chunks "build/statics0legacymul/00000000.dat"
+pub fn read_legacy_mul(data_path: &Path, stem: &str) -> Result<Vec<u8>, Error> {
+    let file = UopFile::open(&get_legacy_uop_path(data_path, stem))?;
+    let data = file.read_chunks(&format!("build/{}legacymul/", stem.to_lowercase()))?;
+    if data.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidData, format!("{} has no data chunks", file.path().display())))
+    }
+
+    Ok(data)
+}
+
+/// returns the path of "{stem}LegacyMUL.uop", resolved case-insensitively
+pub fn get_legacy_uop_path(data_path: &Path, stem: &str) -> PathBuf {
+    get_file_path_ci(data_path, &format!("{stem}LegacyMUL.uop"))
+}
+
+/// Adler-32 checksum, as stored in the entries of UOP files
+fn adler32(data: &[u8]) -> u32 {
+    const MOD_ADLER: u32 = 65521;
+    let (mut a, mut b) = (1u32, 0u32);
+
+    // the sums do not overflow u32 within 5552 bytes
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= MOD_ADLER;
+        b %= MOD_ADLER;
+    }
+
+    (b << 16) | a
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Write};
+    use flate2::write::ZlibEncoder;
+    use flate2::Compression;
+
+    /// header of a UOP file whose first block of the index is at `first_block`
+    fn header(first_block: u64) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&UOP_MAGIC.to_le_bytes());
+        data.extend_from_slice(&5u32.to_le_bytes());
+        data.extend_from_slice(&0u32.to_le_bytes());
+        data.extend_from_slice(&first_block.to_le_bytes());
+        data.extend_from_slice(&100u32.to_le_bytes());
+        data.extend_from_slice(&u32::MAX.to_le_bytes());    // the count is not trusted
+        data
+    }
+
+    /// an empty block of the index
+    fn block(next_block: u64) -> Vec<u8> {
+        let mut data = 0u32.to_le_bytes().to_vec();
+        data.extend_from_slice(&next_block.to_le_bytes());
+        data
+    }
+
+    fn open(name: &str, data: &[u8]) -> Result<UopFile, Error> {
+        let path = std::env::temp_dir().join(format!("path_server_{}_{name}.uop", std::process::id()));
+        std::fs::write(&path, data).unwrap();
+        let result = UopFile::open(&path);
+        std::fs::remove_file(&path).unwrap();
+        result
+    }
+
+    #[test]
+    fn index_blocks_must_be_inside_the_file() {
+        let mut data = header(28);
+        data.extend_from_slice(&block(0));
+        assert!(open("valid_chain", &data).is_ok());
+
+        let mut data = header(28);
+        data.extend_from_slice(&block(1000));
+        assert_eq!(open("outside", &data).err().unwrap().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn looped_index_blocks_are_rejected() {
+        // the second block points back to the first one
+        let mut data = header(28);
+        data.extend_from_slice(&block(40));
+        data.extend_from_slice(&block(28));
+        assert_eq!(open("looped", &data).err().unwrap().kind(), ErrorKind::InvalidData);
+    }
+
+    /// a file with the header and the data of an entry after 16 bytes of padding, and the entry itself
+    fn test_entry(header: &[u8], data: &[u8], decompressed_length: usize, is_compressed: u16) -> (Cursor<Vec<u8>>, UopEntry) {
+        let mut file = vec![0; 16];
+        file.extend_from_slice(header);
+        file.extend_from_slice(data);
+
+        let entry = UopEntry {
+            data_offset: 16,
+            header_length: header.len() as u32,
+            compressed_length: data.len() as u32,
+            decompressed_length: decompressed_length as u32,
+            entry_hash: 1,
+            crc: adler32(header),
+            is_compressed,
+        };
+
+        (Cursor::new(file), entry)
+    }
+
+    #[test]
+    fn entries_are_read_and_verified() {
+        let (mut file, entry) = test_entry(b"head", b"plain data", 10, UOP_COMPRESSION_NONE);
+        assert_eq!(UopFile::read_entry(&mut file, &entry).unwrap(), b"plain data");
+
+        let mut broken = entry;
+        broken.crc ^= 1;
+        assert_eq!(UopFile::read_entry(&mut file, &broken).err().unwrap().kind(), ErrorKind::InvalidData);
+
+        // 0 means there is no checksum
+        broken.crc = 0;
+        assert!(UopFile::read_entry(&mut file, &broken).is_ok());
+
+        // the size after decompression must match
+        let (mut file, entry) = test_entry(b"", b"plain data", 11, UOP_COMPRESSION_NONE);
+        assert_eq!(UopFile::read_entry(&mut file, &entry).err().unwrap().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn compressed_entries_are_inflated() {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&[7; 1000]).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let (mut file, entry) = test_entry(b"", &compressed, 1000, UOP_COMPRESSION_ZLIB);
+        assert_eq!(UopFile::read_entry(&mut file, &entry).unwrap(), vec![7; 1000]);
+
+        let (mut file, entry) = test_entry(b"", &compressed, 1000, UOP_COMPRESSION_BWT);
+        assert_eq!(UopFile::read_entry(&mut file, &entry).err().unwrap().kind(), ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn entries_outside_of_the_file_are_rejected() {
+        let (mut file, mut entry) = test_entry(b"head", b"data", 4, UOP_COMPRESSION_NONE);
+        entry.compressed_length = u32::MAX;
+        assert_eq!(UopFile::read_entry(&mut file, &entry).err().unwrap().kind(), ErrorKind::InvalidData);
+
+        entry.compressed_length = 4;
+        entry.header_length = u32::MAX;
+        assert_eq!(UopFile::read_entry(&mut file, &entry).err().unwrap().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn adler32_known_values() {
+        assert_eq!(adler32(b""), 0x00000001);
+        assert_eq!(adler32(b"a"), 0x00620062);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E60398);
+    }
+
+    #[test]
+    fn adler32_long_input() {
+        // longer than one chunk of 5552 bytes, with the maximum byte values, compared with the sums reduced at every byte
+        let data = vec![0xFF; 20000];
+        let (mut a, mut b) = (1u32, 0u32);
+        for &byte in &data {
+            a = (a + byte as u32) % 65521;
+            b = (b + a) % 65521;
+        }
+
+        assert_eq!(adler32(&data), (b << 16) | a);
+    }
+}
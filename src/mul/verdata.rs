@@ -0,0 +1,115 @@
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use log::trace;
+
+use crate::mul::mulreader::{get_file_path_ci, mul_read_u32};
+
+
+// file_id, block_id, offset, length and an unknown field
+const VERDATA_ENTRY_SIZE: u64 = 20;
+
+/// patch of one block of some data file, the data is stored in verdata.mul itself
+#[derive(Debug, Copy, Clone)]
+pub struct VerdataEntry {
+    pub file_id: u32,
+    pub block_id: u32,  // meaning depends on the file, e.g. index of the sprite or of the multi-object
+    pub offset: u32,
+    pub length: u32,
+}
+
+
+/// patches of legacy clients for several data files, stored in a single verdata.mul
+pub struct Verdata {
+    path: PathBuf,
+    file_len: u64,
+    entries: Vec<VerdataEntry>,
+}
+
+impl Verdata {
+    /// reads the list of patches, returns None if there is no verdata.mul
+    pub fn read(data_path: &Path) -> Result<Option<Self>, Error> {
+        let path = get_file_path_ci(data_path, "verdata.mul");
+        if !path.exists() {
+            return Ok(None)
+        }
+
+        trace!("Verdata::read");
+        let f = File::open(&path)?;
+        let file_len = f.metadata()?.len();
+        let f = &mut BufReader::new(f);
+        let count = mul_read_u32(f)?;
+
+        // the count is not trusted for the allocation, the list can't be longer than the file
+        let max_count = file_len.saturating_sub(4) / VERDATA_ENTRY_SIZE;
+        let mut entries = Vec::with_capacity(count.min(max_count as u32) as usize);
+        for _ in 0..count {
+            entries.push(VerdataEntry {
+                file_id: mul_read_u32(f)?,
+                block_id: mul_read_u32(f)?,
+                offset: mul_read_u32(f)?,
+                length: mul_read_u32(f)?,
+            });
+            let _extra = mul_read_u32(f)?;  // unknown, e.g. dimensions of a gump
+        }
+
+        Ok(Some(Self { path, file_len, entries }))
+    }
+
+    #[inline]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// returns the patches of the file with the given id, in the order of the list,
+    /// a later patch of the same block overrides the earlier one
+    pub fn entries(&self, file_id: u32) -> impl Iterator<Item = &VerdataEntry> {
+        self.entries.iter().filter(move |entry| entry.file_id == file_id && entry.offset != 0xFFFF_FFFF)
+    }
+
+    /// reads the data of the patch, a patch which doesn't fit into the file is rejected before anything is allocated
+    pub fn read_entry(&self, entry: &VerdataEntry) -> Result<Vec<u8>, Error> {
+        if entry.offset as u64 + entry.length as u64 > self.file_len {
+            return Err(Error::new(ErrorKind::InvalidData,
+                format!("patch of block {} of file {} is outside of {}", entry.block_id, entry.file_id, self.path.display())))
+        }
+
+        let f = &mut BufReader::new(File::open(&self.path)?);
+        f.seek(SeekFrom::Start(entry.offset as u64))?;
+
+        let mut data = vec![0; entry.length as usize];
+        f.read_exact(&mut data)?;
+        Ok(data)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::test_data::TestData;
+
+    #[test]
+    fn count_of_entries_is_not_trusted() {
+        let data = TestData::new();
+        let mut file = TestData::verdata(&[(14, 1, vec![0; 12])]);
+        file[..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        data.write("verdata.mul", &file);
+
+        // the list ends before the count is reached, there is no huge allocation before that
+        assert_eq!(Verdata::read(&data.path).err().unwrap().kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn entries_outside_of_the_file_are_rejected() {
+        let data = TestData::new();
+        let mut file = TestData::verdata(&[(14, 1, vec![1; 12]), (14, 2, vec![2; 12])]);
+        file[4 + 20 + 12..4 + 20 + 16].copy_from_slice(&u32::MAX.to_le_bytes());    // length of the second entry
+        data.write("verdata.mul", &file);
+
+        let verdata = Verdata::read(&data.path).unwrap().unwrap();
+        let entries: Vec<_> = verdata.entries(14).copied().collect();
+        assert_eq!(verdata.read_entry(&entries[0]).unwrap(), vec![1; 12]);
+        assert_eq!(verdata.read_entry(&entries[1]).err().unwrap().kind(), ErrorKind::InvalidData);
+    }
+}
@@ -1,3 +1,4 @@
+use std::collections::HashSet;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use doryen_rs::{App, AppOptions, Console, DoryenApi, Engine, TextAlign, UpdateEvent};
@@ -21,6 +22,8 @@ pub struct MulViewer {
     ground_z: i8,
     max_z: i8,
 
+    show_fov: bool,
+
     next_step: Instant,
 }
 
@@ -39,6 +42,8 @@ impl MulViewer {
             ground_z: 0,
             max_z: 127,
 
+            show_fov: false,
+
             next_step: Instant::now(),
         }
     }
@@ -126,6 +131,18 @@ impl MulViewer {
         let surveyor = WorldSurveyor::new(world);
         let mut tiles = Vec::with_capacity(64);
 
+        // tiles visible from the eyes of the character, others are shaded
+        let visible = if self.show_fov {
+            const EYE_HEIGHT: i8 = 15;
+            const VIEW_RANGE: isize = 18;
+
+            let mut points = Vec::new();
+            surveyor.field_of_view(self.current_x, self.current_y, self.current_z.saturating_add(EYE_HEIGHT), VIEW_RANGE, &mut points);
+            Some(points.iter().map(|p| (p.x, p.y)).collect::<HashSet<_>>())
+        } else {
+            None
+        };
+
         for y in y_world..y_world + area_height {
             for x in x_world..x_world + area_width {
                 tiles.clear();
@@ -149,12 +166,12 @@ impl MulViewer {
                         }
 
                         // no draw roofs
-                        if tile_flag & MulTileFlags::Roof as u32 != 0 {
+                        if tile_flag & MulTileFlags::Roof as u64 != 0 {
                             continue;
                         }
 
                         // no draw roofs if there is something over your head
-                        if tile_flag & MulTileFlags::Roof as u32 != 0 && self.max_z < 127 {
+                        if tile_flag & MulTileFlags::Roof as u64 != 0 && self.max_z < 127 {
                             continue;
                         }
 
@@ -185,11 +202,17 @@ impl MulViewer {
                     let draw_tile = draw_tile.unwrap();
 
                     let tile_color = world.world_tile_color(draw_tile);
+                    let tile_color = match &visible {
+                        Some(visible) if !visible.contains(&(x as isize, y as isize)) => {
+                            (tile_color.0 / 4, tile_color.1 / 4, tile_color.2 / 4, tile_color.3)
+                        }
+                        _ => tile_color,
+                    };
                     let tile_flags = world.world_tile_flag(draw_tile);
 
-                    let impassable = tile_flags & MulTileFlags::Impassable as u32 != 0;
-                    let water = tile_flags & MulTileFlags::Wet as u32 != 0;
-                    let door = tile_flags & MulTileFlags::Door as u32 != 0;
+                    let impassable = tile_flags & MulTileFlags::Impassable as u64 != 0;
+                    let water = tile_flags & MulTileFlags::Wet as u64 != 0;
+                    let door = tile_flags & MulTileFlags::Door as u64 != 0;
                     let land = draw_tile.is_land();
                     let slope = draw_tile.is_slope();
 
@@ -235,7 +258,7 @@ impl MulViewer {
                 (0, 0, 0, 255)
             };
 
-            let fcolor = if flags & MulTileFlags::Impassable as u32 != 0 {
+            let fcolor = if flags & MulTileFlags::Impassable as u64 != 0 {
                 (255, 0, 0, 255)
             } else {
                 (255, 255, 255, 255)
@@ -350,6 +373,10 @@ impl Engine for MulViewer {
             self.current_world = self.world_model.next_world_idx(self.current_world);
         }
 
+        if input.key_pressed("KeyF") {
+            self.show_fov = !self.show_fov;
+        }
+
         None
     }
 
@@ -0,0 +1,92 @@
+use std::io::{Error, Write};
+use std::thread;
+use log::debug;
+
+use crate::world::WorldSurveyor;
+
+/*
+    walkability map format, all values are little-endian
+
+    header:
+        magic: [u8; 4]  - "PSWM"
+        version: u8     - 1
+        world: u8
+        left: i32
+        top: i32
+        width: u32
+        height: u32
+
+    then `width` * `height` tiles, row by row, from left to right and from top to bottom.
+    each tile is:
+        count: u8                   - number of levels at which a character can stand
+        levels: [(i8, u8); count]   - z of the level and mask of possible steps from it,
+                                      bit N is set if it is possible to step in the direction N,
+                                      0 is north, 1 is north-east and so on clockwise
+ */
+
+pub const WALKABILITY_MAGIC: &[u8; 4] = b"PSWM";
+pub const WALKABILITY_VERSION: u8 = 1;
+
+/// writes the walkability map of the area left <= x < right, top <= y < bottom to `writer`.
+/// the area is split into bands of rows, which are processed in parallel
+pub fn export_walkability<W: Write>(surveyor: &WorldSurveyor, world: u8, left: isize, top: isize, right: isize, bottom: isize, writer: &mut W) -> Result<(), Error> {
+    let width = (right - left).max(0);
+    let height = (bottom - top).max(0);
+
+    writer.write_all(WALKABILITY_MAGIC)?;
+    writer.write_all(&[WALKABILITY_VERSION, world])?;
+    writer.write_all(&(left as i32).to_le_bytes())?;
+    writer.write_all(&(top as i32).to_le_bytes())?;
+    writer.write_all(&(width as u32).to_le_bytes())?;
+    writer.write_all(&(height as u32).to_le_bytes())?;
+
+    let threads = thread::available_parallelism().map(|n| n.get()).unwrap_or(1) as isize;
+    let band_height = (height + threads - 1) / threads.max(1);
+
+    let bands: Vec<Vec<u8>> = thread::scope(|scope| {
+        let handles: Vec<_> = (0..threads)
+            .map(|band| {
+                let band_top = top + band * band_height;
+                let band_bottom = (band_top + band_height).min(bottom);
+                scope.spawn(move || export_rows(surveyor, left, band_top, right, band_bottom))
+            })
+            .collect();
+
+        handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+    });
+
+    for band in bands {
+        writer.write_all(&band)?;
+    }
+
+    debug!("walkability map of world {world} {width}x{height} is exported");
+    Ok(())
+}
+
+/// returns the encoded tiles of the rows top <= y < bottom
+fn export_rows(surveyor: &WorldSurveyor, left: isize, top: isize, right: isize, bottom: isize) -> Vec<u8> {
+    let mut result = Vec::new();
+    let mut levels = Vec::with_capacity(8);
+
+    for y in top..bottom {
+        for x in left..right {
+            levels.clear();
+            surveyor.standable_z_levels(x, y, &mut levels);
+
+            result.push(levels.len() as u8);
+            for &z in &levels {
+                let mut mask = 0u8;
+                for direction in 0..8 {
+                    if surveyor.test_step(x, y, z, direction).is_some() {
+                        mask |= 1 << direction;
+                    }
+                }
+
+                result.push(z as u8);
+                result.push(mask);
+            }
+        }
+    }
+
+    result
+}
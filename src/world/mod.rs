@@ -3,6 +3,14 @@ pub mod world_model;
 pub mod surveyor;
 pub mod quadtree;
 pub mod tiles;
+pub mod sight;
+pub mod export;
+pub mod tour;
+pub mod navmesh;
+pub mod step_cache;
+pub mod step_table;
+#[cfg(test)]
+pub mod test_data;
 
 pub use world::DynamicWorld;
 
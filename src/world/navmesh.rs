@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use log::trace;
+use serde::{Deserialize, Serialize};
+
+use crate::world::WorldSurveyor;
+
+
+/// a place where a character can stand, and z of the destination for each of 8 directions,
+/// or None if it is impossible to step in that direction
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NavNode {
+    pub x: isize,
+    pub y: isize,
+    pub z: i8,
+    pub steps: [Option<i8>; 8],
+}
+
+
+/// walk-surface graph of one 8x8 block, one node for each standable x, y, z.
+/// nodes are sorted by coordinates
+pub struct NavBlock {
+    pub nodes: Vec<NavNode>,
+}
+
+impl NavBlock {
+    fn build(surveyor: &WorldSurveyor, bx: isize, by: isize) -> Self {
+        let mut nodes = Vec::with_capacity(64);
+        let mut levels = Vec::with_capacity(8);
+
+        for x in bx * 8..bx * 8 + 8 {
+            for y in by * 8..by * 8 + 8 {
+                levels.clear();
+                surveyor.standable_z_levels(x, y, &mut levels);
+
+                for &z in &levels {
+                    nodes.push(NavNode {
+                        x,
+                        y,
+                        z,
+                        steps: std::array::from_fn(|direction| surveyor.test_step_single(x, y, z, direction as u8)),
+                    });
+                }
+            }
+        }
+
+        Self { nodes }
+    }
+
+    /// returns the node with the given coordinates, if there is one
+    pub fn node(&self, x: isize, y: isize, z: i8) -> Option<&NavNode> {
+        self.nodes
+            .binary_search_by(|node| (node.x, node.y, node.z).cmp(&(x, y, z)))
+            .ok()
+            .map(|index| &self.nodes[index])
+    }
+}
+
+
+// walkable and ignore flags of the surveyor, blocks differ for different sets of flags
+type NavFlags = (u64, u64);
+
+/// cache of walk-surface blocks of the world, blocks are built on demand and
+/// rebuilt after changes in the dynamic items around them
+pub struct NavMesh {
+    blocks: RwLock<HashMap<usize, HashMap<NavFlags, Arc<NavBlock>>>>,
+    generation: AtomicU64,  // incremented on each invalidation, to discard blocks built from outdated data
+}
+
+impl NavMesh {
+    // when the cache grows to this number of blocks, it is cleared completely
+    const MAX_BLOCKS: usize = 65536;
+
+    pub fn new() -> Self {
+        Self {
+            blocks: RwLock::new(HashMap::new()),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    /// returns the block with coordinates bx, by, builds it if it is not in the cache
+    pub fn block(&self, surveyor: &WorldSurveyor, bx: isize, by: isize) -> Arc<NavBlock> {
+        let (bx, by) = surveyor.model.base.normalize_blocks(bx, by);
+        let index = surveyor.model.base.block_index(bx, by);
+        let flags = (surveyor.walkable_flags(), surveyor.ignore_flags());
+
+        if let Some(block) = self.blocks.read().unwrap().get(&index).and_then(|blocks| blocks.get(&flags)) {
+            return block.clone()
+        }
+
+        // the block is built without holding the lock, the world may change in the meantime
+        let generation = self.generation.load(Ordering::Acquire);
+        let block = Arc::new(NavBlock::build(surveyor, bx, by));
+        trace!("navigation block {bx}x{by} is built, {} nodes", block.nodes.len());
+
+        let mut blocks = self.blocks.write().unwrap();
+        if generation == self.generation.load(Ordering::Acquire) {
+            if blocks.len() >= Self::MAX_BLOCKS {
+                blocks.clear();
+            }
+            blocks.entry(index).or_default().insert(flags, block.clone());
+        }
+
+        block
+    }
+
+    /// returns the destination z of the step from the given position, if the position is a node of the graph.
+    /// returns None if there is no such node, in this case the step must be tested directly
+    pub fn step(&self, surveyor: &WorldSurveyor, x: isize, y: isize, z: i8, direction: u8) -> Option<Option<i8>> {
+        let (x, y) = surveyor.model.base.normalize_tiles(x, y);
+        let block = self.block(surveyor, x / 8, y / 8);
+
+        block.node(x, y, z).map(|node| node.steps[direction as usize & 7])
+    }
+
+    /// collects the nodes of the area left <= x < right, top <= y < bottom, sorted by coordinates
+    pub fn nodes_in_area(&self, surveyor: &WorldSurveyor, left: isize, top: isize, right: isize, bottom: isize, result: &mut Vec<NavNode>) {
+        for bx in left.div_euclid(8)..=(right - 1).div_euclid(8) {
+            for by in top.div_euclid(8)..=(bottom - 1).div_euclid(8) {
+                let block = self.block(surveyor, bx, by);
+                result.extend(block.nodes.iter()
+                    .filter(|node| node.x >= left && node.x < right && node.y >= top && node.y < bottom)
+                    .cloned());
+            }
+        }
+
+        result.sort_by_key(|node| (node.x, node.y, node.z));
+    }
+
+    /// removes from the cache the block with the given index for all sets of flags
+    pub fn invalidate(&self, index: usize) {
+        let mut blocks = self.blocks.write().unwrap();
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        blocks.remove(&index);
+    }
+}
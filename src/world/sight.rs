@@ -0,0 +1,344 @@
+use std::collections::HashMap;
+use crate::http::server::{Point, SightBlocker};
+use crate::mul::tiledata::{MulTileFlags, StaticTileData};
+use crate::world::{TileType, WorldSurveyor};
+
+
+// land tiles that can be seen through, like in `TileShape::from_land_tile`
+#[inline]
+fn is_land_ignored(tile: u16) -> bool {
+    tile == 0x0002 || tile == 0x01DB || (0x01AE..=0x01B5).contains(&tile)
+}
+
+// "no draw" land tile, it blocks the sight if there are no statics on it
+const LAND_INVALID: u16 = 0x0244;
+
+// flags of the tiles blocking the sight
+const SIGHT_BLOCKING: u64 = MulTileFlags::Window as u64 | MulTileFlags::NoShoot as u64 | MulTileFlags::Wall as u64;
+
+/// the maximum radius of the field of view, the count of scanned tiles grows as its square
+pub const MAX_FOV_RADIUS: isize = 64;
+
+// multipliers transforming coordinates of the first octant to each of the eight octants
+const OCTANTS: [(isize, isize, isize, isize); 8] = [
+    (1, 0, 0, 1), (0, 1, 1, 0), (0, -1, 1, 0), (-1, 0, 0, 1),
+    (-1, 0, 0, -1), (0, -1, -1, 0), (0, 1, -1, 0), (1, 0, 0, -1),
+];
+
+/// height of the tile as the server calculates it, bridges are half as high
+#[inline]
+fn calc_height(tile: &StaticTileData) -> i16 {
+    if tile.flags & MulTileFlags::Bridge as u64 != 0 {
+        tile.height as i16 / 2
+    } else {
+        tile.height as i16
+    }
+}
+
+
+impl WorldSurveyor<'_> {
+    /// checks the line of sight between two points in the same way as server emulators do.
+    /// Returns the tile blocking the sight, or None if the destination point is visible.
+    /// z-coordinates are used as is, so for a character, add its eye height to them.
+    pub fn line_of_sight(&self, s_x: isize, s_y: isize, s_z: i8, d_x: isize, d_y: isize, d_z: i8) -> Option<SightBlocker> {
+        if (s_x, s_y, s_z) == (d_x, d_y, d_z) {
+            return None
+        }
+
+        let ends = [(s_x, s_y, s_z as i16), (d_x, d_y, d_z as i16)];
+
+        // the line is always built in the same direction, so that the result does not depend on the order of points
+        let ((s_x, s_y, s_z), (d_x, d_y, d_z)) = if (s_x, s_y, s_z) > (d_x, d_y, d_z) {
+            ((d_x, d_y, d_z), (s_x, s_y, s_z))
+        } else {
+            ((s_x, s_y, s_z), (d_x, d_y, d_z))
+        };
+
+        for (x, y, z) in Self::sight_line(s_x, s_y, s_z, d_x, d_y, d_z) {
+            if let Some(blocker) = self.sight_blocker_at(x, y, z, z + 1, &ends) {
+                return Some(blocker)
+            }
+        }
+
+        None
+    }
+
+    /// returns all tiles visible from the point x, y, z within the `radius`, using recursive shadow casting.
+    /// A tile is opaque if something in it blocks the sight at the level z, opaque tiles themselves are visible.
+    /// For each tile, `z` is the top of the highest tile below the eye level, `w` is 1 for opaque tiles.
+    pub fn field_of_view(&self, x: isize, y: isize, z: i8, radius: isize, points: &mut Vec<Point>) {
+        let mut opacity = HashMap::new();
+        opacity.insert((x, y), false);
+
+        for &octant in &OCTANTS {
+            self.cast_light(x, y, z, 1, 1.0, 0.0, radius, octant, &mut opacity);
+        }
+
+        let mut tiles = Vec::with_capacity(16);
+        for (&(tx, ty), &opaque) in &opacity {
+            tiles.clear();
+            self.get_tile_objects(tx, ty, 0, &mut tiles);
+            let surface_z = tiles.iter()
+                .map(|tile| tile.z_top())
+                .filter(|&z_top| z_top <= z)
+                .max()
+                .unwrap_or(z);
+
+            points.push(Point { x: tx, y: ty, z: surface_z, w: opaque as isize });
+        }
+
+        points.sort_by_key(|p| (p.x, p.y));
+    }
+
+    /// scans one octant row by row, starting from `row`, between the slopes `start` and `end`.
+    /// Every scanned tile is added to `opacity`, which also caches opacity checks between octants
+    #[allow(clippy::too_many_arguments)]
+    fn cast_light(&self, x: isize, y: isize, z: i8, row: isize, mut start: f64, end: f64, radius: isize,
+                  octant: (isize, isize, isize, isize), opacity: &mut HashMap<(isize, isize), bool>) {
+        if start < end {
+            return
+        }
+
+        let (xx, xy, yx, yy) = octant;
+        let eye = [(x, y, z as i16)];
+        let mut new_start = 0.0;
+
+        for distance in row..=radius {
+            let dy = -distance;
+            let mut blocked = false;
+
+            for dx in -distance..=0 {
+                let l_slope = (dx as f64 - 0.5) / (dy as f64 + 0.5);
+                let r_slope = (dx as f64 + 0.5) / (dy as f64 - 0.5);
+
+                if start < r_slope {
+                    continue
+                } else if end > l_slope {
+                    break
+                }
+
+                let tx = x + dx * xx + dy * xy;
+                let ty = y + dx * yx + dy * yy;
+                let opaque = *opacity.entry((tx, ty)).or_insert_with(|| {
+                    self.sight_blocker_at(tx, ty, z as i16, z as i16 + 1, &eye).is_some()
+                });
+
+                if blocked {
+                    if opaque {
+                        new_start = r_slope;
+                    } else {
+                        blocked = false;
+                        start = new_start;
+                    }
+                } else if opaque && distance < radius {
+                    // the beginning of the shadow, scan the rest of the octant above it
+                    blocked = true;
+                    self.cast_light(x, y, z, distance + 1, start, l_slope, radius, octant, opacity);
+                    new_start = r_slope;
+                }
+            }
+
+            if blocked {
+                break
+            }
+        }
+    }
+
+    /// returns the tiles the line passes through, without repetitions, the last point is always the destination
+    fn sight_line(s_x: isize, s_y: isize, s_z: i8, d_x: isize, d_y: isize, d_z: i8) -> Vec<(isize, isize, i16)> {
+        let (xd, yd, zd) = ((d_x - s_x) as f64, (d_y - s_y) as f64, (d_z as i16 - s_z as i16) as f64);
+        let len = (xd * xd + yd * yd + zd * zd).sqrt();
+        let (run, rise, zslp) = (xd / len, yd / len, zd / len);
+
+        let between = |value: f64, a: isize, b: isize| {
+            let (low, high) = if a < b { (a as f64, b as f64) } else { (b as f64, a as f64) };
+            value > low - 0.5 && value < high + 0.5
+        };
+
+        let mut result = Vec::new();
+        let (mut x, mut y, mut z) = (s_x as f64, s_y as f64, s_z as f64);
+        while between(x, s_x, d_x) && between(y, s_y, d_y) && between(z, s_z as isize, d_z as isize) {
+            let point = (x.round() as isize, y.round() as isize, z.round() as i16);
+            if result.last() != Some(&point) {
+                result.push(point);
+            }
+
+            x += run;
+            y += rise;
+            z += zslp;
+        }
+
+        let dest = (d_x, d_y, d_z as i16);
+        if result.last() != Some(&dest) {
+            result.push(dest);
+        }
+
+        result
+    }
+
+    /// checks if something in the tile x, y blocks the sight between z_low and z_high.
+    /// Objects containing one of the `ends` do not block, since the viewer and the target may be inside them
+    fn sight_blocker_at(&self, x: isize, y: isize, z_low: i16, z_high: i16, ends: &[(isize, isize, i16)]) -> Option<SightBlocker> {
+        let world = self.model;
+        let tiledata = &world.data.tiledata;
+        let contains_end = |low: i16, high: i16| ends.iter()
+            .any(|&(end_x, end_y, end_z)| end_x == x && end_y == y && low <= end_z + 1 && high >= end_z);
+
+        // land is blocking by itself, regardless of flags
+        let land = world.query_tile_ground(x, y, 0, 0);
+        let land_tile = land.tile.num();
+        let land_low = land.z_base() as i16;
+        let land_high = [(0, 0), (1, 0), (1, 1), (0, 1)].iter()
+            .map(|(dx, dy)| world.base.land_vertex_z(x + dx, y + dy) as i16)
+            .max()
+            .unwrap();
+
+        if land_low <= z_high && land_high >= z_low
+            && !contains_end(land_low, land_high)
+            && !is_land_ignored(land_tile) {
+            return Some(SightBlocker { x, y, z: land_low as i8, tile: land_tile, land: true })
+        }
+
+        let mut objects = Vec::with_capacity(16);
+        world.query_tile_static(x, y, 0, 0, &mut objects);
+        world.query_tile_dynamic(x, y, 0, 0, &mut objects);
+
+        if land_tile == LAND_INVALID && objects.is_empty() {
+            return Some(SightBlocker { x, y, z: land_low as i8, tile: land_tile, land: true })
+        }
+
+        for object in &objects {
+            let tile = match object.tile {
+                TileType::ObjectTile(tile) => tile,
+                TileType::MapTile(_) => continue,
+            };
+
+            let tile_data = tiledata.get_static_tile(tile);
+            if tile_data.flags & SIGHT_BLOCKING == 0 {
+                continue
+            }
+
+            let obj_low = object.z_base() as i16;
+            let obj_high = obj_low + calc_height(tile_data);
+
+            if obj_low <= z_high && obj_high >= z_low {
+                if contains_end(obj_low, obj_high) {
+                    continue
+                }
+
+                return Some(SightBlocker { x, y, z: obj_low as i8, tile, land: false })
+            }
+        }
+
+        None
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::world::WorldModel;
+    use crate::world::world_model::FacetSpec;
+    use crate::world::test_data::{TestData, GRASS, WALL, WINDOW};
+    use crate::world::tiles::TopLevelItem;
+    use super::*;
+
+    // a wall along y = 5 from x = 10 to 14, and a window at 12, 12
+    fn walled(data: &TestData) -> WorldModel {
+        let mut statics: Vec<_> = (10..=14).map(|x| (x, 5, 0, WALL)).collect();
+        statics.push((12, 12, 0, WINDOW));
+        data.write_world(0, 4, 4, &statics);
+        data.model(&[FacetSpec { world: 0, width: 4, height: 4, format: None, files: None, wrap: false }])
+    }
+
+    fn blocker(surveyor: &WorldSurveyor, from: (isize, isize, i8), to: (isize, isize, i8)) -> Option<(isize, isize, u16)> {
+        surveyor.line_of_sight(from.0, from.1, from.2, to.0, to.1, to.2)
+            .map(|blocker| (blocker.x, blocker.y, blocker.tile))
+    }
+
+    #[test]
+    fn walls_block_the_sight() {
+        let data = TestData::new();
+        let model = walled(&data);
+        let surveyor = WorldSurveyor::new(model.world(0).unwrap());
+
+        assert_eq!(blocker(&surveyor, (12, 2, 10), (12, 8, 10)), Some((12, 5, WALL)));
+        assert_eq!(blocker(&surveyor, (12, 9, 10), (12, 15, 10)), Some((12, 12, WINDOW)));
+        assert_eq!(blocker(&surveyor, (4, 2, 10), (4, 8, 10)), None);
+
+        // over the wall, and at the wall itself
+        assert_eq!(blocker(&surveyor, (12, 2, 30), (12, 8, 30)), None);
+        assert_eq!(blocker(&surveyor, (12, 2, 10), (12, 5, 10)), None);
+
+        // the line goes through the ground
+        assert_eq!(blocker(&surveyor, (4, 2, 10), (4, 8, -10)), Some((4, 5, GRASS)));
+    }
+
+    #[test]
+    fn items_block_the_sight() {
+        let data = TestData::new();
+        let model = walled(&data);
+        assert!(model.insert_item(TopLevelItem::new(0, 20, 10, 0, 1, WALL as u32, 0)));
+
+        let surveyor = WorldSurveyor::new(model.world(0).unwrap());
+        assert_eq!(blocker(&surveyor, (17, 10, 10), (23, 10, 10)), Some((20, 10, WALL)));
+    }
+
+    #[test]
+    fn line_of_sight_is_symmetric() {
+        let data = TestData::new();
+        let model = walled(&data);
+        let surveyor = WorldSurveyor::new(model.world(0).unwrap());
+
+        let points = [(9, 2, 5), (15, 8, 12), (11, 7, 0), (13, 1, 25), (12, 14, 8), (3, 6, 15), (14, 5, 20)];
+        for &from in &points {
+            for &to in &points {
+                assert_eq!(blocker(&surveyor, from, to), blocker(&surveyor, to, from), "{from:?} - {to:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn field_of_view_is_shadowed_by_walls() {
+        let data = TestData::new();
+        let model = walled(&data);
+        let surveyor = WorldSurveyor::new(model.world(0).unwrap());
+
+        let mut points = Vec::new();
+        surveyor.field_of_view(12, 9, 10, 6, &mut points);
+        let visible = |x: isize, y: isize| points.iter().find(|p| (p.x, p.y) == (x, y)).map(|p| (p.z, p.w));
+
+        assert_eq!(visible(12, 9), Some((0, 0)));
+        assert_eq!(visible(6, 9), Some((0, 0)));
+        assert_eq!(visible(17, 13), Some((0, 0)));
+
+        // the wall and the window are visible themselves, tiles behind them are not
+        assert_eq!(visible(12, 5), Some((0, 1)));
+        assert_eq!(visible(12, 12), Some((0, 1)));
+        assert_eq!(visible(12, 4), None);
+        assert_eq!(visible(12, 14), None);
+
+        // the radius is measured as the greater of dx, dy
+        assert_eq!(visible(18, 9), Some((0, 0)));
+        assert_eq!(visible(19, 9), None);
+        assert!(points.iter().all(|p| (p.x - 12).abs() <= 6 && (p.y - 9).abs() <= 6));
+
+        // over the wall
+        points.clear();
+        surveyor.field_of_view(12, 9, 30, 6, &mut points);
+        assert!(points.iter().any(|p| (p.x, p.y) == (12, 4)));
+        assert!(points.iter().all(|p| p.w == 0));
+        assert_eq!(points.iter().find(|p| (p.x, p.y) == (12, 5)).map(|p| p.z), Some(20));
+    }
+
+    #[test]
+    fn field_of_view_of_zero_radius_is_the_eye() {
+        let data = TestData::new();
+        let model = walled(&data);
+        let surveyor = WorldSurveyor::new(model.world(0).unwrap());
+
+        let mut points = Vec::new();
+        surveyor.field_of_view(3, 3, 10, 0, &mut points);
+        assert_eq!(points.iter().map(|p| (p.x, p.y)).collect::<Vec<_>>(), vec![(3, 3)]);
+    }
+}
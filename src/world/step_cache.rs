@@ -0,0 +1,257 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use log::trace;
+
+use crate::world::WorldSurveyor;
+
+
+/// counters of the step cache
+#[derive(Debug, Clone)]
+pub struct StepCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub blocks: usize,
+    pub entries: usize,
+}
+
+
+// walkable and ignore flags of the surveyor, the same step may have different results for different flags
+type StepFlags = (u64, u64);
+// x, y, z and direction of the step
+type StepKey = (isize, isize, i8, u8);
+
+// results of steps from the tiles of one block
+struct CachedBlock {
+    steps: HashMap<(StepFlags, StepKey), Option<i8>>,
+    used: u64,
+}
+
+// one shard of the cache with its own lock, blocks are distributed between shards by their index
+struct StepCacheShard {
+    blocks: HashMap<usize, CachedBlock>,
+    tick: u64,          // incremented on each access, used to find the least recently used blocks
+    generation: u64,    // incremented on each invalidation, to discard results computed from outdated data
+    entries: usize,
+}
+
+/// results of `test_step_single` shared between all searches in the world.
+/// Results are grouped by blocks of the source tile, the blocks are split between shards,
+/// so that concurrent searches in different blocks don't wait for each other.
+/// When a shard grows above its limit, its least recently used blocks are evicted
+pub struct StepCache {
+    shards: Vec<Mutex<StepCacheShard>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl StepCache {
+    // number of shards, a power of two
+    const SHARDS: usize = 64;
+    // maximum number of cached blocks in each shard
+    const MAX_SHARD_BLOCKS: usize = 16384 / Self::SHARDS;
+
+    pub fn new() -> Self {
+        Self {
+            shards: (0..Self::SHARDS)
+                .map(|_| Mutex::new(StepCacheShard {
+                    blocks: HashMap::new(),
+                    tick: 0,
+                    generation: 0,
+                    entries: 0,
+                }))
+                .collect(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    #[inline]
+    fn shard(&self, index: usize) -> &Mutex<StepCacheShard> {
+        &self.shards[index & (Self::SHARDS - 1)]
+    }
+
+    /// returns the result of the step from x, y, z in the `direction`, tests the step if it is not in the cache.
+    /// The second value is true if the result is taken from the cache
+    pub fn step(&self, surveyor: &WorldSurveyor, x: isize, y: isize, z: i8, direction: u8) -> (Option<i8>, bool) {
+        let base = &surveyor.model.base;
+        let (nx, ny) = base.normalize_tiles(x, y);
+        let index = base.block_index(nx / 8, ny / 8);
+        let key = ((surveyor.walkable_flags(), surveyor.ignore_flags()), (nx, ny, z, direction));
+        let shard = self.shard(index);
+
+        let generation = {
+            let mut shard = shard.lock().unwrap();
+            shard.tick += 1;
+            let tick = shard.tick;
+
+            if let Some(block) = shard.blocks.get_mut(&index) {
+                block.used = tick;
+                if let Some(&result) = block.steps.get(&key) {
+                    self.hits.fetch_add(1, Ordering::Relaxed);
+                    return (result, true)
+                }
+            }
+
+            shard.generation
+        };
+
+        // the step is tested without holding the lock, the world may change in the meantime
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let result = surveyor.test_step_single(x, y, z, direction);
+
+        let mut shard = shard.lock().unwrap();
+        if shard.generation == generation {
+            if !shard.blocks.contains_key(&index) && shard.blocks.len() >= Self::MAX_SHARD_BLOCKS {
+                self.evict(&mut shard);
+            }
+
+            let tick = shard.tick;
+            let block = shard.blocks.entry(index).or_insert_with(|| CachedBlock { steps: HashMap::new(), used: tick });
+            if block.steps.insert(key, result).is_none() {
+                shard.entries += 1;
+            }
+        }
+
+        (result, false)
+    }
+
+    /// removes a quarter of the blocks of the shard, at least one, the least recently used ones
+    fn evict(&self, shard: &mut StepCacheShard) {
+        let mut used: Vec<u64> = shard.blocks.values().map(|block| block.used).collect();
+        if used.is_empty() {
+            return
+        }
+
+        let count = (used.len() / 4).max(1);
+        let (_, &mut threshold, _) = used.select_nth_unstable(count - 1);
+
+        let before = shard.blocks.len();
+        let mut removed_entries = 0;
+        shard.blocks.retain(|_, block| {
+            let keep = block.used > threshold;
+            if !keep {
+                removed_entries += block.steps.len();
+            }
+            keep
+        });
+
+        let evicted = before - shard.blocks.len();
+        shard.entries -= removed_entries;
+        self.evictions.fetch_add(evicted as u64, Ordering::Relaxed);
+        trace!("step cache: {evicted} blocks evicted");
+    }
+
+    /// removes the results of the steps from the block with the given index
+    pub fn invalidate(&self, index: usize) {
+        let mut shard = self.shard(index).lock().unwrap();
+        shard.generation += 1;
+        if let Some(block) = shard.blocks.remove(&index) {
+            shard.entries -= block.steps.len();
+        }
+    }
+
+    pub fn stats(&self) -> StepCacheStats {
+        let (mut blocks, mut entries) = (0, 0);
+        for shard in &self.shards {
+            let shard = shard.lock().unwrap();
+            blocks += shard.blocks.len();
+            entries += shard.entries;
+        }
+
+        StepCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            blocks,
+            entries,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::server::TileFlags;
+    use crate::world::WorldModel;
+    use crate::world::world_model::FacetSpec;
+    use crate::world::test_data::{TestData, WALL};
+    use crate::world::tiles::TopLevelItem;
+
+    const EAST: u8 = 2;
+
+    fn grass(data: &TestData) -> WorldModel {
+        data.write_world(0, 4, 4, &[]);
+        data.model(&[FacetSpec { world: 0, width: 4, height: 4, format: None, files: None, wrap: false }])
+    }
+
+    #[test]
+    fn steps_are_cached_by_flags() {
+        let data = TestData::new();
+        let model = grass(&data);
+        let world = model.world(0).unwrap();
+        let cache = StepCache::new();
+
+        let surveyor = WorldSurveyor::new(world);
+        assert_eq!(cache.step(&surveyor, 5, 5, 0, EAST), (Some(0), false));
+        assert_eq!(cache.step(&surveyor, 5, 5, 0, EAST), (Some(0), true));
+
+        // the same step with other flags and the same flags in another block
+        let wet = WorldSurveyor::new_with_flags(world, vec![TileFlags::Wet], vec![]);
+        assert_eq!(cache.step(&wet, 5, 5, 0, EAST), (Some(0), false));
+        assert_eq!(cache.step(&surveyor, 20, 5, 0, EAST), (Some(0), false));
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.blocks, stats.entries), (1, 3, 2, 3));
+    }
+
+    #[test]
+    fn items_invalidate_steps_into_their_tiles() {
+        let data = TestData::new();
+        let model = grass(&data);
+        let world = model.world(0).unwrap();
+        let surveyor = WorldSurveyor::new(world);
+
+        // the step from the previous block into the tile at its edge
+        assert_eq!(world.step_cache.step(&surveyor, 7, 5, 0, EAST), (Some(0), false));
+        assert_eq!(world.step_cache.step(&surveyor, 7, 5, 0, EAST), (Some(0), true));
+
+        assert!(model.insert_item(TopLevelItem::new(0, 8, 5, 0, 1, WALL as u32, 0)));
+        assert_eq!(world.step_cache.step(&surveyor, 7, 5, 0, EAST), (None, false));
+
+        model.delete_item(1);
+        assert_eq!(world.step_cache.step(&surveyor, 7, 5, 0, EAST), (Some(0), false));
+    }
+
+    #[test]
+    fn least_recently_used_blocks_are_evicted() {
+        let cache = StepCache::new();
+        let mut shard = cache.shards[0].lock().unwrap();
+        for index in 0..8 {
+            let mut steps = HashMap::new();
+            steps.insert(((0, 0), (0, 0, 0, index as u8)), None);
+            // block 3 is the most recently used one
+            let used = if index == 3 { 100 } else { index as u64 };
+            shard.blocks.insert(index * StepCache::SHARDS, CachedBlock { steps, used });
+            shard.entries += 1;
+        }
+
+        cache.evict(&mut shard);
+        let mut remaining: Vec<usize> = shard.blocks.keys().map(|index| index / StepCache::SHARDS).collect();
+        remaining.sort();
+        assert_eq!(remaining, vec![2, 3, 4, 5, 6, 7]);
+        assert_eq!(shard.entries, 6);
+        assert_eq!(cache.evictions.load(Ordering::Relaxed), 2);
+
+        // a single block is evicted too
+        shard.blocks.retain(|&index, _| index == 3 * StepCache::SHARDS);
+        shard.entries = 1;
+        cache.evict(&mut shard);
+        assert!(shard.blocks.is_empty());
+        assert_eq!(shard.entries, 0);
+    }
+}
@@ -0,0 +1,479 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::UNIX_EPOCH;
+use log::{debug, info, warn};
+use memmap2::Mmap;
+
+use crate::mul::mulreader::{get_file_path_ci, get_world_file_path};
+use crate::mul::uop::get_legacy_uop_path;
+use crate::world::{DynamicWorld, WorldSurveyor};
+
+/*
+    step table format, all values are little-endian
+
+    header:
+        magic: [u8; 4]      - "PSST"
+        version: u8         - 1
+        world: u8
+        reserved: [u8; 2]
+        width_blocks: u32
+        height_blocks: u32
+        hash: u64           - hash of the data files the table is built from, see `hash_data_files`
+
+    offsets: [u64; width_blocks * height_blocks + 1]
+        offsets of the blocks from the beginning of the file, in the order of block indices,
+        the last one is the end of the file
+
+    each block is:
+        tiles: [u16; 65]    - offsets of the tile records from the end of this array,
+                              tile x, y has index (y % 8) * 8 + x % 8, the last one is the end of the block
+        then records of the tiles, for each level at which a character can stand:
+            z: i8
+            mask: u8                    - bit N is set if it is possible to step in the direction N
+            dest: [i8; popcount(mask)]  - z of the destination for each possible direction, in the order of directions
+ */
+
+const STEP_TABLE_MAGIC: &[u8; 4] = b"PSST";
+const STEP_TABLE_VERSION: u8 = 1;
+const HEADER_SIZE: usize = 24;
+const TILE_OFFSETS_SIZE: usize = 65 * 2;
+
+// 64-bit FNV-1a, simple and stable between builds, unlike the hasher of the standard library
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+// how many bytes are hashed at the beginning and at the end of the large data files
+const HASHED_SAMPLE_SIZE: u64 = 0x10000;
+
+
+/// results of the steps over land and statics with default flags, precomputed for the whole world
+/// and stored in a memory-mapped file next to the data files
+pub struct StepTable {
+    data: Mmap,
+    blocks: usize,
+}
+
+impl StepTable {
+    /// opens the step table of the world. If the file is missing or built from other data files,
+    /// builds it when `build` is set, otherwise returns an error.
+    /// Must be called before any items are added to the world
+    pub fn open_or_build(data_path: &Path, world: u8, model: &DynamicWorld, build: bool) -> Result<Self, Error> {
+        let path = Self::file_path(data_path, world);
+        let hash = Self::hash_data_files(data_path, model)?;
+
+        match Self::open(&path, world, model, hash) {
+            Ok(table) => {
+                debug!("step table of world {world} is loaded from {}", path.display());
+                return Ok(table)
+            }
+
+            Err(err) if build => info!("step table of world {world} needs to be built: {err}"),
+            Err(err) => return Err(err),
+        }
+
+        // the table is written to a new file and then replaces the old one, which may still be mapped
+        // by another process, so that the mapped contents never change
+        let temp_path = path.with_extension(format!("{}.tmp", std::process::id()));
+        let built = Self::build(&temp_path, world, model, hash).and_then(|_| fs::rename(&temp_path, &path));
+        if let Err(err) = built {
+            let _ = fs::remove_file(&temp_path);
+            return Err(err)
+        }
+
+        Self::open(&path, world, model, hash)
+    }
+
+    pub fn file_path(data_path: &Path, world: u8) -> PathBuf {
+        data_path.join(format!("steptable{world}.bin"))
+    }
+
+    /// returns the destination z of the step from x, y, z in the `direction`, or None if the table
+    /// has no level z at x, y. Coordinates must be normalized
+    pub fn step(&self, model: &DynamicWorld, x: isize, y: isize, z: i8, direction: u8) -> Option<Option<i8>> {
+        let index = model.base.block_index(x / 8, y / 8);
+        if index >= self.blocks {
+            return None
+        }
+
+        let offset_at = HEADER_SIZE + index * 8;
+        let block_start = u64::from_le_bytes(self.data[offset_at..offset_at + 8].try_into().unwrap()) as usize;
+
+        let tile = ((y % 8) * 8 + x % 8) as usize;
+        let tile_at = block_start + tile * 2;
+        let records_start = block_start + TILE_OFFSETS_SIZE;
+        let start = records_start + u16::from_le_bytes([self.data[tile_at], self.data[tile_at + 1]]) as usize;
+        let end = records_start + u16::from_le_bytes([self.data[tile_at + 2], self.data[tile_at + 3]]) as usize;
+
+        let records = &self.data[start..end];
+        let mut pos = 0;
+        while pos + 2 <= records.len() {
+            let level = records[pos] as i8;
+            let mask = records[pos + 1];
+            if level == z {
+                if mask & (1 << direction) == 0 {
+                    return Some(None)
+                }
+
+                let dest_at = pos + 2 + (mask & ((1 << direction) - 1)).count_ones() as usize;
+                return Some(Some(records[dest_at] as i8))
+            }
+
+            pos += 2 + mask.count_ones() as usize;
+        }
+
+        None
+    }
+
+    fn open(path: &Path, world: u8, model: &DynamicWorld, hash: u64) -> Result<Self, Error> {
+        let file = File::open(path)?;
+        let data = unsafe { Mmap::map(&file)? };
+
+        let invalid = |reason: &str| Error::new(ErrorKind::InvalidData, reason.to_string());
+        let (width_blocks, height_blocks) = (model.base.width() / 8, model.base.height() / 8);
+        let blocks = width_blocks * height_blocks;
+
+        if data.len() < HEADER_SIZE + (blocks + 1) * 8 {
+            return Err(invalid("file is too short"))
+        }
+
+        if &data[0..4] != STEP_TABLE_MAGIC || data[4] != STEP_TABLE_VERSION || data[5] != world {
+            return Err(invalid("unknown format"))
+        }
+
+        let read_u32 = |at: usize| u32::from_le_bytes(data[at..at + 4].try_into().unwrap()) as usize;
+        let read_u64 = |at: usize| u64::from_le_bytes(data[at..at + 8].try_into().unwrap());
+
+        if read_u32(8) != width_blocks || read_u32(12) != height_blocks {
+            return Err(invalid("world dimensions do not match"))
+        }
+
+        if read_u64(16) != hash {
+            return Err(invalid("data files have changed"))
+        }
+
+        if read_u64(HEADER_SIZE + blocks * 8) as usize != data.len() {
+            return Err(invalid("file is truncated"))
+        }
+
+        // `step` trusts the offsets and the records, so all of them are checked once here
+        let read_u16 = |at: usize| u16::from_le_bytes([data[at], data[at + 1]]) as usize;
+        let mut block_start = HEADER_SIZE + (blocks + 1) * 8;
+        for index in 0..blocks {
+            let block_end = read_u64(HEADER_SIZE + (index + 1) * 8) as usize;
+            if read_u64(HEADER_SIZE + index * 8) as usize != block_start
+                || block_end < block_start + TILE_OFFSETS_SIZE || block_end > data.len() {
+                return Err(invalid("block offsets are out of order"))
+            }
+
+            let records_start = block_start + TILE_OFFSETS_SIZE;
+            if read_u16(block_start) != 0 || records_start + read_u16(block_start + TILE_OFFSETS_SIZE - 2) != block_end {
+                return Err(invalid("tile offsets do not match the block"))
+            }
+
+            for tile in 0..64 {
+                let start = records_start + read_u16(block_start + tile * 2);
+                let end = records_start + read_u16(block_start + tile * 2 + 2);
+                if end < start || end > block_end {
+                    return Err(invalid("tile offsets are out of order"))
+                }
+
+                let mut pos = start;
+                while pos < end {
+                    pos += 2 + if pos + 1 < end { data[pos + 1].count_ones() as usize } else { 0 };
+                }
+
+                if pos != end {
+                    return Err(invalid("tile records do not match the tile offsets"))
+                }
+            }
+
+            block_start = block_end;
+        }
+
+        Ok(Self { data, blocks })
+    }
+
+    fn build(path: &Path, world: u8, model: &DynamicWorld, hash: u64) -> Result<(), Error> {
+        info!("building step table of world {world}, it may take a while...");
+        let surveyor = WorldSurveyor::new(model);
+        let (width_blocks, height_blocks) = (model.base.width() / 8, model.base.height() / 8);
+
+        // blocks are stored in the order of indices, bx * height_blocks + by, so the world is split into bands of columns
+        let threads = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+        let band_width = (width_blocks + threads - 1) / threads.max(1);
+
+        let bands: Vec<Vec<Vec<u8>>> = thread::scope(|scope| {
+            let surveyor = &surveyor;
+            let handles: Vec<_> = (0..threads)
+                .map(|band| {
+                    let band_left = (band * band_width).min(width_blocks);
+                    let band_right = (band_left + band_width).min(width_blocks);
+                    scope.spawn(move || {
+                        (band_left..band_right)
+                            .flat_map(|bx| (0..height_blocks).map(move |by| (bx, by)))
+                            .map(|(bx, by)| Self::build_block(surveyor, bx as isize, by as isize))
+                            .collect()
+                    })
+                })
+                .collect();
+
+            handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+        });
+
+        let mut writer = BufWriter::new(File::options().write(true).create_new(true).open(path)?);
+        writer.write_all(STEP_TABLE_MAGIC)?;
+        writer.write_all(&[STEP_TABLE_VERSION, world, 0, 0])?;
+        writer.write_all(&(width_blocks as u32).to_le_bytes())?;
+        writer.write_all(&(height_blocks as u32).to_le_bytes())?;
+        writer.write_all(&hash.to_le_bytes())?;
+
+        let mut offset = (HEADER_SIZE + (width_blocks * height_blocks + 1) * 8) as u64;
+        for block in bands.iter().flatten() {
+            writer.write_all(&offset.to_le_bytes())?;
+            offset += block.len() as u64;
+        }
+        writer.write_all(&offset.to_le_bytes())?;
+
+        for block in bands.iter().flatten() {
+            writer.write_all(block)?;
+        }
+
+        writer.flush()?;
+        info!("step table of world {world} is built, {} bytes", offset);
+        Ok(())
+    }
+
+    /// returns the encoded block bx, by
+    fn build_block(surveyor: &WorldSurveyor, bx: isize, by: isize) -> Vec<u8> {
+        let mut tile_offsets = Vec::with_capacity(65);
+        let mut records = Vec::with_capacity(256);
+        let mut levels = Vec::with_capacity(8);
+
+        for oy in 0..8 {
+            for ox in 0..8 {
+                let (x, y) = (bx * 8 + ox, by * 8 + oy);
+                tile_offsets.push(records.len() as u16);
+
+                levels.clear();
+                surveyor.standable_z_levels(x, y, &mut levels);
+                for &z in &levels {
+                    let mask_at = records.len() + 1;
+                    records.push(z as u8);
+                    records.push(0);
+
+                    for direction in 0..8 {
+                        if let Some(dest_z) = surveyor.test_step_single(x, y, z, direction) {
+                            records[mask_at] |= 1 << direction;
+                            records.push(dest_z as u8);
+                        }
+                    }
+                }
+            }
+        }
+        tile_offsets.push(records.len() as u16);
+
+        let mut result = Vec::with_capacity(TILE_OFFSETS_SIZE + records.len());
+        for offset in tile_offsets {
+            result.extend_from_slice(&offset.to_le_bytes());
+        }
+        result.extend_from_slice(&records);
+        result
+    }
+
+    /// hashes the dimensions of the world and the names, sizes and modification times of the files the steps depend on.
+    /// The contents of tiledata, the index of statics and the patches are hashed completely. Only the beginning
+    /// and the end of the map and statics are hashed, reading them whole would take longer than loading the world,
+    /// so a change in the middle of these files that keeps their size and modification time is not noticed
+    fn hash_data_files(data_path: &Path, model: &DynamicWorld) -> Result<u64, Error> {
+        // the data files may have a different number than the world
+        let world = model.base.file_index;
+        let map_path = match model.base.use_mul {
+            true => get_world_file_path(data_path, "map", world, ".mul"),
+            false => get_world_file_path(data_path, "map", world, "LegacyMUL.uop"),
+        };
+
+        // files packed into UOP are used only if there are no MUL files
+        let mul_or_uop = |mul_path: PathBuf, stem: &str| match mul_path {
+            path if path.exists() => path,
+            _ => get_legacy_uop_path(data_path, stem),
+        };
+
+        // (path, whether the whole file is hashed)
+        let mut paths = vec![
+            (map_path, false),
+            (mul_or_uop(get_world_file_path(data_path, "staidx", world, ".mul"), &format!("staidx{world}")), true),
+            (mul_or_uop(get_world_file_path(data_path, "statics", world, ".mul"), &format!("statics{world}")), false),
+            (mul_or_uop(get_file_path_ci(data_path, "tiledata.mul"), "tiledata"), true),
+        ];
+
+        // tiledata may be patched by verdata.mul, it is applied whenever present
+        let verdata_path = get_file_path_ci(data_path, "verdata.mul");
+        if verdata_path.exists() {
+            paths.push((verdata_path, true));
+        }
+
+        if model.base.patched {
+            for name in ["mapdifl", "mapdif", "stadifl", "stadifi", "stadif"] {
+                let path = get_file_path_ci(data_path, &format!("{name}{world}.mul"));
+                if path.exists() {
+                    paths.push((path, true));
+                }
+            }
+        }
+
+        let mut hash = FNV_OFFSET;
+        let mut fnv = |bytes: &[u8]| {
+            for &byte in bytes {
+                hash = (hash ^ byte as u64).wrapping_mul(FNV_PRIME);
+            }
+        };
+
+        fnv(&(model.base.width() as u64).to_le_bytes());
+        fnv(&(model.base.height() as u64).to_le_bytes());
+
+        for (path, whole) in &paths {
+            let meta = fs::metadata(path)?;
+            let modified = meta.modified()?
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_nanos() as u64);
+
+            fnv(path.file_name().map_or(&[][..], |name| name.as_encoded_bytes()));
+            fnv(&meta.len().to_le_bytes());
+            fnv(&modified.to_le_bytes());
+
+            if *whole || meta.len() <= 2 * HASHED_SAMPLE_SIZE {
+                fnv(&fs::read(path)?);
+            } else {
+                let mut file = File::open(path)?;
+                let mut sample = vec![0; HASHED_SAMPLE_SIZE as usize];
+                file.read_exact(&mut sample)?;
+                fnv(&sample);
+                file.seek(SeekFrom::End(-(HASHED_SAMPLE_SIZE as i64)))?;
+                file.read_exact(&mut sample)?;
+                fnv(&sample);
+            }
+        }
+
+        Ok(hash)
+    }
+}
+
+
+impl DynamicWorld {
+    /// opens or builds the precomputed step table of the world, if it fails the world works without it.
+    /// Must be called before any items are added to the world
+    pub fn load_step_table(&self, data_path: &Path, world: u8, build: bool) {
+        match StepTable::open_or_build(data_path, world, self, build) {
+            Ok(table) => {
+                if self.step_table.set(table).is_err() {
+                    warn!("step table of world {world} is already loaded");
+                }
+            }
+
+            Err(err) => warn!("step table of world {world} is not used: {err}"),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::WorldModel;
+    use crate::world::world_model::FacetSpec;
+    use crate::world::test_data::{TestData, WALL};
+
+    const NORTH: u8 = 0;
+    const EAST: u8 = 2;
+
+    fn walled(data: &TestData) -> WorldModel {
+        data.write_world(0, 2, 2, &[(6, 5, 0, WALL)]);
+        data.model(&[FacetSpec { world: 0, width: 2, height: 2, format: None, files: None, wrap: false }])
+    }
+
+    fn open_modified(data: &TestData, model: &DynamicWorld, modify: impl FnOnce(&mut Vec<u8>)) -> Result<StepTable, Error> {
+        let mut bytes = fs::read(StepTable::file_path(&data.path, 0)).unwrap();
+        modify(&mut bytes);
+
+        let path = data.path.join("modified.bin");
+        fs::write(&path, &bytes).unwrap();
+        StepTable::open(&path, 0, model, StepTable::hash_data_files(&data.path, model).unwrap())
+    }
+
+    fn error_of(result: Result<StepTable, Error>) -> String {
+        match result {
+            Ok(_) => panic!("the table is accepted"),
+            Err(err) => {
+                assert_eq!(err.kind(), ErrorKind::InvalidData);
+                err.to_string()
+            }
+        }
+    }
+
+    #[test]
+    fn built_table_matches_the_steps() {
+        let data = TestData::new();
+        let model = walled(&data);
+        let world = model.world(0).unwrap();
+
+        assert!(StepTable::open_or_build(&data.path, 0, world, false).is_err());
+        let table = StepTable::open_or_build(&data.path, 0, world, true).unwrap();
+        assert_eq!(table.step(world, 5, 5, 0, EAST), Some(None));
+        assert_eq!(table.step(world, 5, 5, 0, NORTH), Some(Some(0)));
+        assert_eq!(table.step(world, 5, 5, 7, NORTH), None);
+
+        let surveyor = WorldSurveyor::new(world);
+        for (x, y) in [(0, 0), (5, 4), (7, 5), (15, 15)] {
+            for direction in 0..8 {
+                assert_eq!(table.step(world, x, y, 0, direction), Some(surveyor.test_step_single(x, y, 0, direction)));
+            }
+        }
+
+        // the stored table is used without building
+        assert!(StepTable::open_or_build(&data.path, 0, world, false).is_ok());
+    }
+
+    #[test]
+    fn broken_offsets_are_rejected() {
+        let data = TestData::new();
+        let model = walled(&data);
+        let world = model.world(0).unwrap();
+        StepTable::open_or_build(&data.path, 0, world, true).unwrap();
+
+        assert!(open_modified(&data, world, |_| ()).is_ok());
+        assert_eq!(error_of(open_modified(&data, world, |bytes| { bytes.pop(); })), "file is truncated");
+
+        // the first block does not follow the offsets, the second one starts one byte later
+        assert_eq!(error_of(open_modified(&data, world, |bytes| bytes[HEADER_SIZE] += 1)), "block offsets are out of order");
+        assert_eq!(error_of(open_modified(&data, world, |bytes| bytes[HEADER_SIZE + 8] += 1)), "tile offsets do not match the block");
+
+        // the first tile of the first block ends after the block
+        let first_tile_end = HEADER_SIZE + 5 * 8 + 2;
+        assert_eq!(error_of(open_modified(&data, world, |bytes| bytes[first_tile_end + 1] = 0xFF)), "tile offsets are out of order");
+        assert_eq!(error_of(open_modified(&data, world, |bytes| bytes[first_tile_end] += 1)), "tile records do not match the tile offsets");
+    }
+
+    #[test]
+    fn changed_data_files_are_detected() {
+        let data = TestData::new();
+        let model = walled(&data);
+        let world = model.world(0).unwrap();
+        StepTable::open_or_build(&data.path, 0, world, true).unwrap();
+
+        assert_eq!(error_of(open_modified(&data, world, |bytes| bytes[16] ^= 1)), "data files have changed");
+
+        // tiledata of the same size and modification time, but with another height of the wall
+        let path = data.path.join("tiledata.mul");
+        let modified = fs::metadata(&path).unwrap().modified().unwrap();
+        let mut tiledata = fs::read(&path).unwrap();
+        let height_at = 512 * (4 + 32 * 26) + 4 + WALL as usize * 37 + 16;
+        tiledata[height_at] = 10;
+        data.write("tiledata.mul", &tiledata);
+        File::options().write(true).open(&path).unwrap().set_modified(modified).unwrap();
+
+        let err = StepTable::open_or_build(&data.path, 0, world, false).err().unwrap();
+        assert_eq!(err.to_string(), "data files have changed");
+    }
+}
@@ -1,15 +1,17 @@
+use std::cell::{Cell, RefCell};
 use std::cmp::{Ordering};
 use std::collections::{BinaryHeap, HashMap};
 use std::collections::hash_map::{Entry};
 use std::time::Instant;
-use log::{debug, info, warn};
+use log::{debug, info, trace, warn};
 
-use crate::http::server::{DistanceFunc, Point, TileFlags, TraceOptions};
+use crate::http::server::{Area, DistanceFunc, Point, Termination, TileFlags, TraceOptions, TraceStats, TraceStatus};
 use crate::mul::tiledata::MulTileFlags;
 use crate::world::{DynamicWorld, TileShape, WorldTile};
+use crate::world::tour;
 
 
-#[derive(Debug, Eq, PartialEq, Hash, Copy, Clone)]
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Copy, Clone)]
 struct Position (isize, isize, i8);
 
 
@@ -18,7 +20,7 @@ struct ScoredPosition (isize, isize, u8, Position, Position);
 
 impl PartialEq for ScoredPosition {
     fn eq(&self, other: &Self) -> bool {
-        self.0 == other.0
+        self.cmp(other) == Ordering::Equal
     }
 }
 
@@ -26,8 +28,14 @@ impl Eq for ScoredPosition {
 }
 
 impl Ord for ScoredPosition {
+    /// the lowest fval is the greatest, on equal fval the highest gval, that is, the nearest to the goal.
+    /// The rest of the fields break the remaining ties, so that the order of exploring is always the same
     fn cmp(&self, other: &Self) -> Ordering {
         other.0.cmp(&self.0)
+            .then(self.1.cmp(&other.1))
+            .then_with(|| other.3.cmp(&self.3))
+            .then_with(|| other.2.cmp(&self.2))
+            .then_with(|| other.4.cmp(&self.4))
     }
 }
 
@@ -38,10 +46,24 @@ impl PartialOrd for ScoredPosition {
     }
 }
 
+// the room a character needs above the surface it stands on
+const CHARACTER_HEIGHT: i16 = 16;
+
+/// checks if a character standing at `z_stand` fits under an object with the bottom at `z_ceiling`
+#[inline]
+fn has_room(z_stand: i16, z_ceiling: i16) -> bool {
+    z_ceiling - z_stand >= CHARACTER_HEIGHT
+}
+
+// results of the steps in each direction from the positions expanded by the searches of one request,
+// kept in front of the shared caches of the world, so that repeated searches in the same area,
+// like growing corridors or tour floods, don't lock them again
+type StepMap = HashMap<Position, [Option<Option<i8>>; 8]>;
+
 pub struct WorldSurveyor<'a> {
     pub model: &'a DynamicWorld,
-    walkable: u32,
-    ignore: u32,
+    walkable: u64,
+    ignore: u64,
     fly: bool,
 }
 
@@ -72,10 +94,20 @@ impl<'a> WorldSurveyor<'a> {
             model,
             walkable,
             ignore,
-            fly: walkable & MulTileFlags::HoverOver as u32 != 0
+            fly: walkable & MulTileFlags::HoverOver as u64 != 0
         }
     }
 
+    #[inline]
+    pub fn walkable_flags(&self) -> u64 {
+        self.walkable
+    }
+
+    #[inline]
+    pub fn ignore_flags(&self) -> u64 {
+        self.ignore
+    }
+
     /// returns a vector of elements located at the given coordinates and used in movement testing
     pub fn get_tile_objects(&self, x: isize, y: isize, direction: u8, result: &mut Vec<WorldTile>) {
         self.model.query_tile_full(x, y, direction, self.walkable, self.ignore, result);
@@ -155,9 +187,8 @@ impl<'a> WorldSurveyor<'a> {
                 }
             }
 
-            const CHARACTER_HEIGHT: i16 = 16;
             // character can fit between upper_obj_z_base and z_low
-            if upper_obj_z_base - z_low >= CHARACTER_HEIGHT {
+            if has_room(z_low, upper_obj_z_base) {
                 // check the tiles below in reverse order
                 for bottom_obj in objects[..i].iter().rev() {
                     let (bottom_obj_z_stand, passable) = match bottom_obj.shape {
@@ -176,7 +207,7 @@ impl<'a> WorldSurveyor<'a> {
 
                     // if the tile is walkable, it is higher than the last viewed "upper" tile and
                     // there is enough room for the character to stand between it and the upper_tile_z_base
-                    if passable && bottom_obj_z_stand >= current_z && (upper_obj_z_base - bottom_obj_z_stand) >= CHARACTER_HEIGHT {
+                    if passable && bottom_obj_z_stand >= current_z && has_room(bottom_obj_z_stand, upper_obj_z_base) {
                         // check if we can reach it from our z_high, given the type of the object
                         if !match bottom_obj.shape {
                             TileShape::Slope { z_base, .. }   => z_base as i16 <= z_high,
@@ -211,6 +242,47 @@ impl<'a> WorldSurveyor<'a> {
         result
     }
 
+    /// adds to `result` all z-coordinates at which a character can stand in the given tile,
+    /// i.e. passable surfaces with enough room above them, in ascending order
+    pub fn standable_z_levels(&self, x: isize, y: isize, result: &mut Vec<i8>) {
+        let mut tiles = Vec::with_capacity(16);
+        self.get_tile_objects(x, y, 0, &mut tiles);
+
+        for (i, tile) in tiles.iter().enumerate() {
+            let z_stand = match tile.shape {
+                TileShape::Slope { z_stand, passable: true, .. } |
+                TileShape::Surface { z_stand, passable: true, .. } => z_stand,
+                TileShape::HoverOver { z_base } if self.fly => z_base,
+                _ => continue,
+            };
+
+            // any other surface between the feet and the head of the character does not leave room for it
+            let blocked = tiles.iter().enumerate().any(|(j, other)| {
+                j != i && match other.shape {
+                    TileShape::Slope { .. } | TileShape::Surface { .. } => {
+                        other.z_top() > z_stand && !has_room(z_stand as i16, other.z_base() as i16)
+                    }
+                    TileShape::HoverOver { .. } | TileShape::Background { .. } => false,
+                }
+            });
+
+            if !blocked && !result.contains(&z_stand) {
+                result.push(z_stand);
+            }
+        }
+
+        result.sort();
+    }
+
+    /// returns the standable z-coordinate of the tile nearest to `z`, the lower one if two are equally near,
+    /// or None if there is no place to stand in the tile
+    pub fn nearest_standable_z(&self, x: isize, y: isize, z: i8) -> Option<i8> {
+        let mut levels = Vec::with_capacity(8);
+        self.standable_z_levels(x, y, &mut levels);
+
+        levels.into_iter().min_by_key(|&level| (level as i16 - z as i16).abs())
+    }
+
     #[inline]
     pub fn direction(dx: isize, dy: isize) -> u8 {
         fn signum(n: isize) -> i32 {
@@ -260,7 +332,7 @@ impl<'a> WorldSurveyor<'a> {
     }
 
     /// just checks if it is possible to step from the starting position in the specified direction
-    fn test_step_single(&self, x: isize, y: isize, z: i8, direction: u8) -> Option<i8> {
+    pub fn test_step_single(&self, x: isize, y: isize, z: i8, direction: u8) -> Option<i8> {
         let (to_x, to_y) = Self::move_to(x, y, direction);
         let (z_low, z_high) = self.get_source_step_range(x, y, z, direction);
         self.get_dest_position(to_x, to_y, z, z_low, z_high)
@@ -289,20 +361,370 @@ impl<'a> WorldSurveyor<'a> {
     }
 
 
+    /// returns the position to start the search from, in a wrapped world positions are kept normalized,
+    /// so that each tile is visited once. If it is enabled in the options, z is snapped to the nearest standable level
+    fn start_position(&self, x: isize, y: isize, z: i8, options: &TraceOptions) -> Position {
+        let (x, y) = if self.model.base.wrap {
+            self.model.base.normalize_tiles(x, y)
+        } else {
+            (x, y)
+        };
+
+        Position(x, y, self.snap_z(x, y, z, options))
+    }
+
+    /// returns z snapped to the nearest standable level of the tile, if it is enabled in the options
+    /// and there is a place to stand, otherwise z as is
+    fn snap_z(&self, x: isize, y: isize, z: i8, options: &TraceOptions) -> i8 {
+        if !options.snap_z.unwrap_or(false) {
+            return z
+        }
+
+        match self.nearest_standable_z(x, y, z) {
+            Some(level) => {
+                if level != z {
+                    trace!("z {z} at {x}, {y} is snapped to {level}");
+                }
+                level
+            }
+            None => z,
+        }
+    }
+
+    /// returns the limit of the explored positions, a deterministic search is always limited,
+    /// since the time limit can't be relied on
+    fn node_limit(options: &TraceOptions) -> Option<isize> {
+        match options.node_limit {
+            Some(limit) => Some(limit),
+            None if options.deterministic.unwrap_or(false) => Some(DETERMINISTIC_NODE_LIMIT),
+            None => None,
+        }
+    }
+
+    /// returns the heuristic for the options, aware of the world wrapping if the world is wrapped
+    fn heuristic(&self, options: &TraceOptions) -> Heuristic {
+        let mut heuristic = Heuristic::from_options(options);
+        if self.model.base.wrap {
+            heuristic.wrap = Some((self.model.base.width() as isize, self.model.base.height() as isize));
+        }
+
+        heuristic
+    }
+
+    /// returns the result of `test_step_single` from the precomputed step table if it can be used,
+    /// that is, the flags are default and there are no dynamic items in the source and destination blocks.
+    /// Otherwise the step is taken from the step cache of the world.
+    /// The second value is true if the step has not been tested again
+    fn cached_step(&self, x: isize, y: isize, z: i8, direction: u8) -> (Option<i8>, bool) {
+        if let Some(table) = self.model.step_table.get() {
+            if self.walkable == 0 && self.ignore == 0 {
+                let base = &self.model.base;
+                let (sx, sy) = base.normalize_tiles(x, y);
+                let (dx, dy) = Self::move_to(sx, sy, direction);
+                let (dx, dy) = base.normalize_tiles(dx, dy);
+
+                let has_items = {
+                    let overlay = self.model.read_overlay();
+                    overlay.contains_key(&base.block_index(sx / 8, sy / 8)) || overlay.contains_key(&base.block_index(dx / 8, dy / 8))
+                };
+
+                if !has_items {
+                    if let Some(result) = table.step(self.model, sx, sy, z, direction) {
+                        return (result, true)
+                    }
+                }
+            }
+        }
+
+        self.model.step_cache.step(self, x, y, z, direction)
+    }
+
+    /// checks if a step in any direction is possible from the position, regardless of the search area
+    fn can_step_from(&self, pos: Position) -> bool {
+        let Position(x, y, z) = pos;
+        (0..8).any(|direction| self.cached_step(x, y, z, direction).0.is_some())
+    }
+
     /// searches for a path by algorithm A* from the point s_x,s_y,s_z to the point d_x, d_y, d_z.
     /// `points` will contain the found path to the nearest possible point, or all points explored during the search,
     /// depending on the options.
     /// Also, through `options`, you can fine-tune the parameters of the algorithm, such as the distance function,
     /// heuristic coefficients, boundaries of the path search area.
-    pub fn trace_a_star(&self, s_x: isize, s_y: isize, s_z: i8, sdir: u8, d_x: isize, d_y: isize, d_z: i8, ddir: u8, points: &mut Vec<Point>, options: &TraceOptions) {
-        let mut cached_steps = HashMap::new();
+    /// If the boundaries are not set, the search is limited to a corridor around the start and the goal,
+    /// which grows after each failed search until it covers the whole world.
+    /// In a wrapped world without the boundaries, the whole world is searched at once.
+    /// Returns the status of the search, the statistics of all searches together and the areas that were searched, in order.
+    pub fn trace_a_star(&self, s_x: isize, s_y: isize, s_z: i8, sdir: u8, d_x: isize, d_y: isize, d_z: i8, _ddir: u8, points: &mut Vec<Point>, options: &TraceOptions) -> Trace {
+        let goal = PointGoal {
+            x: d_x,
+            y: d_y,
+            z: self.snap_z(d_x, d_y, d_z, options),
+            accuracy_x: options.accuracy_x.unwrap_or(0),
+            accuracy_y: options.accuracy_y.unwrap_or(0),
+            accuracy_z: options.accuracy_z.unwrap_or(0),
+            heuristic: self.heuristic(options),
+        };
+
+        let start_pos = self.start_position(s_x, s_y, s_z, options);
+        let (width, height) = (self.model.base.width() as isize, self.model.base.height() as isize);
+
+        if options.left.is_some() || options.top.is_some() || options.right.is_some() || options.bottom.is_some() {
+            let mut trace = self.search(start_pos, sdir, &goal, points, options);
+            trace.areas.push(Area {
+                left: options.left.unwrap_or(0),
+                top: options.top.unwrap_or(0),
+                right: options.right.unwrap_or(width),
+                bottom: options.bottom.unwrap_or(height),
+            });
+            return trace
+        }
+
+        // corridors don't cross the edges, so a wrapped world is searched at once
+        if self.model.base.wrap {
+            let mut trace = self.search(start_pos, sdir, &goal, points, options);
+            trace.areas.push(Area { left: 0, top: 0, right: width, bottom: height });
+            return trace
+        }
+
+        let mut margin = options.corridor_margin.unwrap_or(CORRIDOR_MARGIN).max(1);
+        let mut areas = Vec::new();
+        let mut stats = TraceStats::new();
+        let time_limit = options.time_limit;
+        let node_limit = Self::node_limit(options);
+        let mut options = options.clone();
+        let start_time = Instant::now();
+        let mut steps = StepMap::new();
+
+        loop {
+            let area = Area {
+                left: (s_x.min(d_x) - margin).max(0),
+                top: (s_y.min(d_y) - margin).max(0),
+                right: (s_x.max(d_x) + margin + 1).min(width),
+                bottom: (s_y.max(d_y) + margin + 1).min(height),
+            };
+
+            options.left = Some(area.left);
+            options.top = Some(area.top);
+            options.right = Some(area.right);
+            options.bottom = Some(area.bottom);
+            // the time and node limits are shared by all searches
+            options.time_limit = time_limit.map(|limit| (limit - start_time.elapsed().as_millis() as isize).max(0));
+            options.node_limit = node_limit.map(|limit| (limit - stats.nodes_expanded as isize).max(0));
+
+            let whole_world = area.left == 0 && area.top == 0 && area.right == width && area.bottom == height;
+            areas.push(area);
+
+            let result = self.explore(start_pos, sdir, &goal, &options, &mut steps);
+            stats.merge(&result.stats);
+
+            if matches!(result.stats.termination, Termination::Found | Termination::TimeLimit | Termination::NodeLimit) || whole_world {
+                let status = Self::collect_points(start_pos, result, points, &options);
+                return Trace { status, stats, areas }
+            }
+
+            debug!("the goal is not reachable within margin {margin}, expanding the search area");
+            margin *= 2;
+        }
+    }
+
+    /// searches for a path to a moving target, whose last known positions are given in `target`
+    /// (from the oldest to the newest). The target is assumed to keep moving in the same direction
+    /// with the `speed` relative to ours, 1.0 means one tile per `cost_move_straight` of the path cost.
+    /// The search stops at the first explored tile where the predicted target will be at the time we get there.
+    #[allow(clippy::too_many_arguments)]
+    pub fn trace_intercept(&self, s_x: isize, s_y: isize, s_z: i8, sdir: u8, target: &[Point], speed: f64, points: &mut Vec<Point>, options: &TraceOptions) -> Trace {
+        let (first, last) = match (target.first(), target.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => {
+                warn!("no target positions to intercept");
+                return Trace { status: TraceStatus::NoData, stats: TraceStats::new(), areas: Vec::new() }
+            }
+        };
+
+        // direction of the target movement, normalized to one tile per step
+        let (dx, dy) = (last.x - first.x, last.y - first.y);
+        let tiles = dx.abs().max(dy.abs());
+        let cost_move_straight = options.cost_move_straight.unwrap_or(1).max(1);
+        let (vx, vy) = if tiles > 0 {
+            let tiles_per_cost = speed / cost_move_straight as f64;
+            (dx as f64 / tiles as f64 * tiles_per_cost, dy as f64 / tiles as f64 * tiles_per_cost)
+        } else {
+            (0.0, 0.0)
+        };
+
+        debug!("target at ({} {} {}) moves with velocity ({vx:.4} {vy:.4}) tiles per cost unit", last.x, last.y, last.z);
+
+        let goal = InterceptGoal {
+            x: last.x,
+            y: last.y,
+            z: last.z,
+            vx,
+            vy,
+            accuracy_x: options.accuracy_x.unwrap_or(0),
+            accuracy_y: options.accuracy_y.unwrap_or(0),
+            accuracy_z: options.accuracy_z.unwrap_or(0),
+            heuristic: self.heuristic(options),
+        };
+
+        self.search(self.start_position(s_x, s_y, s_z, options), sdir, &goal, points, options)
+    }
+
+    /// searches for the cheapest path to any point at least `distance` tiles away from all `threats`.
+    /// Tiles closer than `threat_radius` to any of the threats are penalized with `cost_threat`
+    /// for each tile of approach, so the path prefers to go around the threats rather than past them.
+    #[allow(clippy::too_many_arguments)]
+    pub fn trace_flee(&self, s_x: isize, s_y: isize, s_z: i8, sdir: u8, threats: &[Point], distance: isize, threat_radius: isize, points: &mut Vec<Point>, options: &TraceOptions) -> Trace {
+        let goal = FleeGoal {
+            threats: threats.iter().map(|p| (p.x, p.y)).collect(),
+            distance,
+            threat_radius,
+            cost_threat: options.cost_threat.unwrap_or(1),
+            heuristic: self.heuristic(options),
+        };
+
+        self.search(self.start_position(s_x, s_y, s_z, options), sdir, &goal, points, options)
+    }
+
+    /// searches for the shortest round through all `waypoints` starting from the point s_x, s_y, s_z.
+    /// Costs between all pairs of points are calculated by flooding the area around each point,
+    /// then the visiting order is found with nearest neighbour heuristic improved by 2-opt.
+    /// If `closed` is true, the tour returns to the starting point.
+    /// At most `MAX_TOUR_WAYPOINTS` waypoints are expected, each of them floods the area once.
+    pub fn trace_tour(&self, s_x: isize, s_y: isize, s_z: i8, waypoints: &[Point], closed: bool, options: &TraceOptions) -> Tour {
+        // if the search area is not set, limit it to the waypoints with some margin,
+        // otherwise each unreachable waypoint floods the whole world
+        const TOUR_MARGIN: isize = 64;
+        let mut options = options.clone();
+        let nodes: Vec<(isize, isize, i8)> = [(s_x, s_y, s_z)].into_iter()
+            .chain(waypoints.iter().map(|p| (p.x, p.y, p.z)))
+            .map(|(x, y, z)| {
+                let Position(x, y, z) = self.start_position(x, y, z, &options);
+                (x, y, z)
+            })
+            .collect();
+
+        // the bounding box doesn't cover the ways over the edges, so a wrapped world is not limited
+        if !self.model.base.wrap {
+            options.left = options.left.or(nodes.iter().map(|n| n.0).min().map(|v| v - TOUR_MARGIN));
+            options.top = options.top.or(nodes.iter().map(|n| n.1).min().map(|v| v - TOUR_MARGIN));
+            options.right = options.right.or(nodes.iter().map(|n| n.0).max().map(|v| v + TOUR_MARGIN + 1));
+            options.bottom = options.bottom.or(nodes.iter().map(|n| n.1).max().map(|v| v + TOUR_MARGIN + 1));
+        }
+
+        // flood from each node until all other nodes are found
+        let mut floods = Vec::with_capacity(nodes.len());
+        let mut steps = StepMap::new();
+        for &(x, y, z) in &nodes {
+            let goal = FloodGoal::new(&nodes, &options, self.heuristic(&options));
+            let result = self.explore(Position(x, y, z), 0, &goal, &options, &mut steps);
+            floods.push((result, goal.found.into_inner()));
+        }
+
+        let costs: Vec<Vec<Option<isize>>> = floods.iter()
+            .map(|(_, found)| found.iter().map(|f| f.map(|(_, gval)| gval)).collect())
+            .collect();
+
+        let solution = tour::solve(&costs, closed);
+
+        // concatenate paths of the legs up to the first one without a path,
+        // so the points never jump over a gap
+        let mut points = Vec::new();
+        let mut cost = 0;
+        let legs = solution.order.iter().copied().chain((closed && !solution.order.is_empty()).then_some(0));
+
+        let mut from = 0;
+        points.push(Point { x: nodes[0].0, y: nodes[0].1, z: nodes[0].2, w: 0 });
+        for to in legs {
+            let (result, found) = &floods[from];
+            let Some((end, gval)) = found[to] else {
+                break
+            };
+
+            let start = Position(nodes[from].0, nodes[from].1, nodes[from].2);
+            let first = points.len();
+            let mut curr_pos = end;
+            while curr_pos != start {
+                points.push(Point { x: curr_pos.0, y: curr_pos.1, z: curr_pos.2, w: 0 });
+                curr_pos = result.back_path[&curr_pos];
+            }
+            points[first..].reverse();
+            cost += gval;
+            from = to;
+        }
+
+        if solution.broken.is_empty() {
+            info!("tour through {} waypoints with cost {}, path len is {} tiles", solution.order.len(), cost, points.len());
+        } else {
+            info!("tour through {} waypoints is broken at {} legs, the other legs cost {}, path len is {} tiles",
+                solution.order.len(), solution.broken.len(), solution.cost, points.len());
+        }
+
+        Tour {
+            // node 0 is the starting point, so waypoints indices are shifted by one
+            order: solution.order.iter().map(|n| n - 1).collect(),
+            unreachable: solution.unreachable.iter().map(|n| n - 1).collect(),
+            broken: solution.broken,
+            cost,
+            points,
+        }
+    }
+
+    /// the A* search itself, the target of the search and its heuristic are defined by `goal`.
+    /// `points` will contain the path to the goal or to the nearest point to it,
+    /// or all points explored during the search, depending on the options.
+    /// Returns the status and the statistics of the search.
+    fn search<G: SearchGoal>(&self, start_pos: Position, sdir: u8, goal: &G, points: &mut Vec<Point>, options: &TraceOptions) -> Trace {
+        let mut result = self.explore(start_pos, sdir, goal, options, &mut StepMap::new());
+        let stats = std::mem::replace(&mut result.stats, TraceStats::new());
+        let status = Self::collect_points(start_pos, result, points, options);
+
+        Trace { status, stats, areas: Vec::new() }
+    }
+
+    /// fills `points` with the path from `start_pos` to the best position of the search result,
+    /// or with all visited positions, depending on the options.
+    /// If the goal is not reached and partial paths are not allowed, `points` stay empty.
+    /// Returns the status of the search
+    fn collect_points(start_pos: Position, result: SearchResult, points: &mut Vec<Point>, options: &TraceOptions) -> TraceStatus {
+        let all_points = options.all_points.unwrap_or(false);
+        let partial = options.partial.unwrap_or(true);
+        let SearchResult { visited, back_path, best_pos, best_dist, status, .. } = result;
+
+        if all_points {
+            for (Position(x, y,z), w) in visited {
+                points.push(Point{ x, y, z, w, });
+            }
+            points.sort_by_key(|p| (p.x, p.y, p.z));
+        } else if status != TraceStatus::Reached && !partial {
+            info!("the goal is not reached, partial path is not returned: {status:?}");
+        } else if let Some(mut curr_pos) = best_pos {
+            let mut cnt = 0;
+            info!("search path to start from {curr_pos:?} with score {best_dist}");
+            while curr_pos != start_pos {
+                cnt += 1;
+                let prev_pos = back_path[&curr_pos];
+                points.push(Point{ x: prev_pos.0, y: prev_pos.1, z: prev_pos.2, w: 0, });
+                curr_pos = prev_pos;
+            }
+            info!("found start, path len is {cnt} tiles!");
+            points.reverse();
+        } else {
+            warn!("there is no data to return after tracing completes");
+            return TraceStatus::NoData
+        }
+
+        status
+    }
+
+    /// explores the world from `start_pos` until the `goal` is reached or the search is out of limits.
+    /// Returns the costs of all visited positions, the paths to them, and the position nearest to the goal.
+    /// Results of the steps are taken from and added to `steps` first.
+    fn explore<G: SearchGoal>(&self, start_pos: Position, sdir: u8, goal: &G, options: &TraceOptions, steps: &mut StepMap) -> SearchResult {
         let mut frontier = BinaryHeap::new();
         let mut visited = HashMap::new();
         let mut back_path = HashMap::new();
-
-        let x_accuracy = options.accuracy_x.unwrap_or(0);
-        let y_accuracy = options.accuracy_y.unwrap_or(0);
-        let z_accuracy = options.accuracy_z.unwrap_or(0);
+        let mut stats = TraceStats::new();
+        let cache_hits = Cell::new(0);
 
         let cost_move_multi = options.cost_move_multi.unwrap_or(0);
         let cost_limit = options.cost_limit.unwrap_or(isize::MAX);
@@ -310,67 +732,59 @@ impl<'a> WorldSurveyor<'a> {
         let cost_move_straight = options.cost_move_straight.unwrap_or(1);
         let cost_move_diagonal = options.cost_move_diagonal.unwrap_or(cost_move_straight);
         let allow_diagonal_move = options.allow_diagonal_move.unwrap_or(false);
-
-        let h_dist = options.heuristic_distance.unwrap_or(DistanceFunc::Diagonal);
-        let h_direct = options.heuristic_straight.unwrap_or(5);
-        let h_diagonal = options.heuristic_diagonal.unwrap_or(h_direct);
+        let use_navmesh = options.use_navmesh.unwrap_or(false);
+        let wrap = self.model.base.wrap;
 
         let left = options.left.unwrap_or(0);
         let top = options.top.unwrap_or(0);
         let right = options.right.unwrap_or(self.model.base.width() as isize);
         let bottom = options.bottom.unwrap_or(self.model.base.height() as isize);
 
-        let all_points = options.all_points.unwrap_or(false);
         let time_limit = options.time_limit.unwrap_or(isize::MAX) as u128;
+        let node_limit = Self::node_limit(options).unwrap_or(isize::MAX).max(0) as usize;
+        let deterministic = options.deterministic.unwrap_or(false);
 
-        let dist_func = |dx: isize, dy: isize| {
-            match h_dist {
-                DistanceFunc::Manhattan => (dx + dy) * h_direct,
-                DistanceFunc::Chebyshev => dx.max(dy) * h_direct,
-                DistanceFunc::Diagonal => h_direct * (dx + dy) + (h_diagonal - 2 * h_direct) * dx.min(dy),
-                DistanceFunc::Euclidean => f64::sqrt((dx * dx + dy * dy) as f64) as isize * h_direct,
-            }
-        };
-
-        let h_func = |position: &Position| {
-            let dx = (d_x - position.0).abs();
-            let dy = (d_y - position.1).abs();
-            dist_func(dx, dy)
-        };
-
-
-        let check_step = |x: isize, y: isize, z: i8, dir: u8, cache: &mut HashMap<(isize, isize, i8, u8), Option<i8>>| {
+        // step results are shared between searches through the cache of the world
+        let check_step = |x: isize, y: isize, z: i8, dir: u8, known: &mut [Option<Option<i8>>; 8]| {
             let (dx, dy) = Self::move_to(x, y, dir);
 
+            // in a wrapped world the boundaries apply to the normalized coordinates
+            let (dx, dy) = if wrap { self.model.base.normalize_tiles(dx, dy) } else { (dx, dy) };
             if dx < left || dx >= right || dy < top || dy >= bottom { // check bounds
                 return None
             }
 
-            match cache.entry((x, y, z, dir)) {
-                Entry::Occupied(entry) => {
-                    *entry.get()
-                }
+            if let Some(result) = known[dir as usize] {
+                cache_hits.set(cache_hits.get() + 1);
+                return result
+            }
 
-                Entry::Vacant(entry) => {
-                    let result = self.test_step_single(x,y,z, dir);
+            let navmesh_step = if use_navmesh { self.model.navmesh.step(self, x, y, z, dir) } else { None };
+            let (result, hit) = match navmesh_step {
+                Some(result) => (result, true),
+                None => self.cached_step(x, y, z, dir),
+            };
 
-                    entry.insert(result);
-                    result
-                }
+            if hit {
+                cache_hits.set(cache_hits.get() + 1);
             }
+            known[dir as usize] = Some(result);
+            result
         };
 
-        let start_pos = Position(s_x, s_y, s_z);
         let start_gval = 0;
-        let start_fval = start_gval + h_func(&start_pos);
+        let start_fval = start_gval + goal.estimate(&start_pos, start_gval);
         let scored_start_pos = ScoredPosition(start_fval, start_gval, sdir, start_pos, Position(-1, -1, -1));
         frontier.push(scored_start_pos);
+        stats.nodes_pushed += 1;
 
         let start_time = Instant::now();
         let mut cnt = 0;
 
         let mut best_dist = isize::MAX;
         let mut best_pos = None;
+        let mut cost_limited = false;
+        let mut termination = None;
 
         while let Some(curr_scored_pos) = frontier.pop() {
             let ScoredPosition(curr_fval, curr_gval, curr_dir, curr_pos, src_pos) = curr_scored_pos;
@@ -379,7 +793,13 @@ impl<'a> WorldSurveyor<'a> {
             cnt += 1;
             if cnt % 1000 == 0 {
                 if start_time.elapsed().as_millis() >= time_limit {
-                    warn!("search time limit reached: {}ms", time_limit);
+                    // the result depends on the speed of the machine
+                    if deterministic {
+                        warn!("deterministic search time limit reached: {}ms, the result is not deterministic", time_limit);
+                    } else {
+                        warn!("search time limit reached: {}ms", time_limit);
+                    }
+                    termination = Some(Termination::TimeLimit);
                     break;
                 }
             }
@@ -399,34 +819,39 @@ impl<'a> WorldSurveyor<'a> {
                 }
             }
 
+            if stats.nodes_expanded >= node_limit {
+                debug!("search node limit reached: {node_limit}");
+                termination = Some(Termination::NodeLimit);
+                break
+            }
+
             back_path.insert(curr_pos, src_pos);
+            stats.nodes_expanded += 1;
 
             // goal check
-            let d_x = (d_x - curr_x).abs();
-            let d_y = (d_y - curr_y).abs();
-            let d_z = (d_z - curr_z).abs() as isize;
-            let d_max = d_x.max(d_y).max(d_z);
-
-            if d_max < best_dist {
+            let dist = goal.distance(&curr_pos, curr_gval);
+            if dist < best_dist {
                 best_pos = Some(curr_pos);
-                best_dist = d_max;
+                best_dist = dist;
             }
 
-            if d_x <= x_accuracy && d_y <= y_accuracy && d_z <= z_accuracy {
+            if goal.is_reached(&curr_pos, curr_gval) {
                 info!("Found! {curr_x} {curr_y} {curr_gval} {curr_fval}");
+                termination = Some(Termination::Found);
                 break
             }
 
-            let dest_n = check_step(curr_x, curr_y, curr_z, 0, &mut cached_steps);
-            let dest_e = check_step(curr_x, curr_y, curr_z, 2, &mut cached_steps);
-            let dest_s = check_step(curr_x, curr_y, curr_z, 4, &mut cached_steps);
-            let dest_w = check_step(curr_x, curr_y, curr_z, 6, &mut cached_steps);
+            let known = steps.entry(curr_pos).or_insert([None; 8]);
+            let dest_n = check_step(curr_x, curr_y, curr_z, 0, known);
+            let dest_e = check_step(curr_x, curr_y, curr_z, 2, known);
+            let dest_s = check_step(curr_x, curr_y, curr_z, 4, known);
+            let dest_w = check_step(curr_x, curr_y, curr_z, 6, known);
 
             let steps = if allow_diagonal_move {
-                let dest_ne = if dest_n.is_some() && dest_e.is_some() { check_step(curr_x, curr_y, curr_z, 1, &mut cached_steps) } else { None };
-                let dest_se = if dest_s.is_some() && dest_e.is_some() { check_step(curr_x, curr_y, curr_z, 3, &mut cached_steps) } else { None };
-                let dest_sw = if dest_s.is_some() && dest_w.is_some() { check_step(curr_x, curr_y, curr_z, 5, &mut cached_steps) } else { None };
-                let dest_nw = if dest_n.is_some() && dest_w.is_some() { check_step(curr_x, curr_y, curr_z, 7, &mut cached_steps) } else { None };
+                let dest_ne = if dest_n.is_some() && dest_e.is_some() { check_step(curr_x, curr_y, curr_z, 1, known) } else { None };
+                let dest_se = if dest_s.is_some() && dest_e.is_some() { check_step(curr_x, curr_y, curr_z, 3, known) } else { None };
+                let dest_sw = if dest_s.is_some() && dest_w.is_some() { check_step(curr_x, curr_y, curr_z, 5, known) } else { None };
+                let dest_nw = if dest_n.is_some() && dest_w.is_some() { check_step(curr_x, curr_y, curr_z, 7, known) } else { None };
 
                 [(0, dest_n), (1, dest_ne), (2, dest_e), (3, dest_se), (4, dest_s), (5, dest_sw), (6, dest_w), (7, dest_nw)]
             } else {
@@ -436,6 +861,7 @@ impl<'a> WorldSurveyor<'a> {
             for (direction, dest_result) in steps {
                 if let Some(dest_z) = dest_result {
                     let (dest_x, dest_y) = Self::move_to(curr_x, curr_y, direction);
+                    let (dest_x, dest_y) = if wrap { self.model.base.normalize_tiles(dest_x, dest_y) } else { (dest_x, dest_y) };
                     let dest_pos = Position(dest_x, dest_y, dest_z);
 
                     match visited.entry(dest_pos) {
@@ -457,14 +883,19 @@ impl<'a> WorldSurveyor<'a> {
                         dest_gval
                     };
 
+                    let dest_gval = dest_gval + goal.penalty(dest_x, dest_y);
+
                     if dest_gval > cost_limit {
+                        cost_limited = true;
                         continue
                     }
 
-                    let dest_fval = dest_gval + h_func(&dest_pos);
+                    let dest_fval = dest_gval + goal.estimate(&dest_pos, dest_gval);
                     let dest_scored = ScoredPosition(dest_fval, dest_gval, direction, dest_pos, curr_pos);
 
                     frontier.push(dest_scored);
+                    stats.nodes_pushed += 1;
+                    stats.peak_frontier = stats.peak_frontier.max(frontier.len());
                 }
             }
         }
@@ -472,27 +903,417 @@ impl<'a> WorldSurveyor<'a> {
         let duration = start_time.elapsed();
         debug!("total tiles explored {cnt} and visited {} in {:?}", visited.len(), duration);
 
-        if all_points {
-            for (Position(x, y,z), w) in visited {
-                points.push(Point{ x, y, z, w, });
-            }
-        } else if let Some(mut curr_pos) = best_pos {
-            cnt = 0;
-            info!("search path to start from {curr_pos:?} with score {best_dist}");
-            loop {
-                cnt += 1;
-                let prev_pos = back_path[&curr_pos];
-                points.push(Point{ x: prev_pos.0, y: prev_pos.1, z: prev_pos.2, w: 0, });
+        // the frontier is exhausted, if some positions were skipped because of the cost limit, the goal may lie behind them
+        stats.termination = termination.unwrap_or(if cost_limited { Termination::CostLimit } else { Termination::Exhausted });
+        stats.reached_goal = stats.termination == Termination::Found;
+        stats.cache_hits = cache_hits.get();
+        stats.elapsed_ms = duration.as_millis() as u64;
 
-                if prev_pos == start_pos {
-                    info!("found start, path len is {cnt} tiles!");
-                    break;
-                }
-                curr_pos = prev_pos;
+        let status = if stats.reached_goal {
+            TraceStatus::Reached
+        } else if !self.can_step_from(start_pos) {
+            TraceStatus::StartBlocked
+        } else {
+            TraceStatus::PartialNearest
+        };
+
+        SearchResult {
+            visited,
+            back_path,
+            best_pos,
+            best_dist,
+            stats,
+            status,
+        }
+    }
+}
+
+
+// default margin of the search corridor around the start and the goal
+const CORRIDOR_MARGIN: isize = 48;
+
+// default limit of the positions explored by a deterministic search, which must not depend on the time
+const DETERMINISTIC_NODE_LIMIT: isize = 1_000_000;
+
+/// the maximum count of waypoints of a tour, the area is flooded once from each of them
+pub const MAX_TOUR_WAYPOINTS: usize = 128;
+
+/// the state of the search after its completion
+struct SearchResult {
+    visited: HashMap<Position, isize>,
+    back_path: HashMap<Position, Position>,
+    best_pos: Option<Position>,
+    best_dist: isize,
+    stats: TraceStats,
+    status: TraceStatus,
+}
+
+
+/// the outcome of the path search, `areas` are set only by `trace_a_star`
+pub struct Trace {
+    pub status: TraceStatus,
+    pub stats: TraceStats,
+    pub areas: Vec<Area>,
+}
+
+
+/// the result of the tour search, `order` and `unreachable` contain indices of the waypoints.
+/// `broken` contains positions in `order` of the waypoints which have no path from the previous one,
+/// `order.len()` stands for the return to the start. `points` and `cost` end at the first broken leg
+pub struct Tour {
+    pub order: Vec<usize>,
+    pub unreachable: Vec<usize>,
+    pub broken: Vec<usize>,
+    pub cost: isize,
+    pub points: Vec<Point>,
+}
+
+
+/// distance function and its coefficients used as a heuristic, see `TraceOptions`
+#[derive(Copy, Clone)]
+struct Heuristic {
+    func: DistanceFunc,
+    straight: isize,
+    diagonal: isize,
+    wrap: Option<(isize, isize)>,   // dimensions of the world, if it is wrapped around at the edges
+}
+
+impl Heuristic {
+    fn from_options(options: &TraceOptions) -> Self {
+        let straight = options.heuristic_straight.unwrap_or(5);
+
+        Self {
+            func: options.heuristic_distance.unwrap_or(DistanceFunc::Diagonal),
+            straight,
+            diagonal: options.heuristic_diagonal.unwrap_or(straight),
+            wrap: None,
+        }
+    }
+
+    /// returns the distances between the points along both axes,
+    /// the shortest way around is taken if the world is wrapped
+    #[inline]
+    fn offsets(&self, x1: isize, y1: isize, x2: isize, y2: isize) -> (isize, isize) {
+        match self.wrap {
+            Some((width, height)) => {
+                let dx = (x1 - x2).rem_euclid(width);
+                let dy = (y1 - y2).rem_euclid(height);
+                (dx.min(width - dx), dy.min(height - dy))
             }
-            points.reverse();
+
+            None => ((x1 - x2).abs(), (y1 - y2).abs()),
+        }
+    }
+
+    /// `dx` and `dy` must be non-negative
+    #[inline]
+    fn distance(&self, dx: isize, dy: isize) -> isize {
+        match self.func {
+            DistanceFunc::Manhattan => (dx + dy) * self.straight,
+            DistanceFunc::Chebyshev => dx.max(dy) * self.straight,
+            DistanceFunc::Diagonal => self.straight * (dx + dy) + (self.diagonal - 2 * self.straight) * dx.min(dy),
+            DistanceFunc::Euclidean => f64::sqrt((dx * dx + dy * dy) as f64) as isize * self.straight,
+        }
+    }
+}
+
+
+/// the target of the search, allows the same A* loop to search for a fixed point or a moving target.
+/// `gval` is the cost of the path to the position, which can also be considered as the time spent
+trait SearchGoal {
+    /// checks if the position satisfies the goal
+    fn is_reached(&self, position: &Position, gval: isize) -> bool;
+
+    /// returns the estimated cost of the rest of the path
+    fn estimate(&self, position: &Position, gval: isize) -> isize;
+
+    /// returns the distance to the goal, used to select the nearest point if the goal is unreachable
+    fn distance(&self, position: &Position, gval: isize) -> isize;
+
+    /// returns the additional cost of stepping on the tile
+    #[inline]
+    fn penalty(&self, _x: isize, _y: isize) -> isize {
+        0
+    }
+}
+
+
+/// fixed destination point with the given accuracy
+struct PointGoal {
+    x: isize,
+    y: isize,
+    z: i8,
+    accuracy_x: isize,
+    accuracy_y: isize,
+    accuracy_z: isize,
+    heuristic: Heuristic,
+}
+
+impl SearchGoal for PointGoal {
+    fn is_reached(&self, position: &Position, _gval: isize) -> bool {
+        let (d_x, d_y) = self.heuristic.offsets(self.x, self.y, position.0, position.1);
+
+        d_x <= self.accuracy_x
+            && d_y <= self.accuracy_y
+            && (self.z - position.2).abs() as isize <= self.accuracy_z
+    }
+
+    fn estimate(&self, position: &Position, _gval: isize) -> isize {
+        let (d_x, d_y) = self.heuristic.offsets(self.x, self.y, position.0, position.1);
+        self.heuristic.distance(d_x, d_y)
+    }
+
+    fn distance(&self, position: &Position, _gval: isize) -> isize {
+        let (d_x, d_y) = self.heuristic.offsets(self.x, self.y, position.0, position.1);
+        let d_z = (self.z - position.2).abs() as isize;
+
+        d_x.max(d_y).max(d_z)
+    }
+}
+
+
+/// target moving in a straight line from the point x, y with the velocity vx, vy tiles per cost unit
+struct InterceptGoal {
+    x: isize,
+    y: isize,
+    z: i8,
+    vx: f64,
+    vy: f64,
+    accuracy_x: isize,
+    accuracy_y: isize,
+    accuracy_z: isize,
+    heuristic: Heuristic,
+}
+
+impl InterceptGoal {
+    /// returns the predicted position of the target at the time `gval`
+    #[inline]
+    fn predicted(&self, gval: isize) -> (isize, isize) {
+        (
+            self.x + (self.vx * gval as f64).round() as isize,
+            self.y + (self.vy * gval as f64).round() as isize,
+        )
+    }
+}
+
+impl SearchGoal for InterceptGoal {
+    fn is_reached(&self, position: &Position, gval: isize) -> bool {
+        let (x, y) = self.predicted(gval);
+        let (d_x, d_y) = self.heuristic.offsets(x, y, position.0, position.1);
+
+        d_x <= self.accuracy_x
+            && d_y <= self.accuracy_y
+            && (self.z - position.2).abs() as isize <= self.accuracy_z
+    }
+
+    fn estimate(&self, position: &Position, gval: isize) -> isize {
+        let (x, y) = self.predicted(gval);
+        let (d_x, d_y) = self.heuristic.offsets(x, y, position.0, position.1);
+        self.heuristic.distance(d_x, d_y)
+    }
+
+    fn distance(&self, position: &Position, gval: isize) -> isize {
+        let (x, y) = self.predicted(gval);
+        let (d_x, d_y) = self.heuristic.offsets(x, y, position.0, position.1);
+        let d_z = (self.z - position.2).abs() as isize;
+
+        d_x.max(d_y).max(d_z)
+    }
+}
+
+
+/// any point far enough from all threats
+struct FleeGoal {
+    threats: Vec<(isize, isize)>,
+    distance: isize,
+    threat_radius: isize,
+    cost_threat: isize,
+    heuristic: Heuristic,
+}
+
+impl FleeGoal {
+    /// returns the distance to the nearest threat
+    #[inline]
+    fn nearest_threat(&self, x: isize, y: isize) -> isize {
+        self.threats.iter()
+            .map(|&(tx, ty)| {
+                let (dx, dy) = self.heuristic.offsets(tx, ty, x, y);
+                dx.max(dy)
+            })
+            .min()
+            .unwrap_or(isize::MAX)
+    }
+}
+
+impl SearchGoal for FleeGoal {
+    fn is_reached(&self, position: &Position, _gval: isize) -> bool {
+        self.nearest_threat(position.0, position.1) >= self.distance
+    }
+
+    fn estimate(&self, position: &Position, _gval: isize) -> isize {
+        self.distance(position, 0) * self.heuristic.straight
+    }
+
+    fn distance(&self, position: &Position, _gval: isize) -> isize {
+        (self.distance - self.nearest_threat(position.0, position.1)).max(0)
+    }
+
+    fn penalty(&self, x: isize, y: isize) -> isize {
+        let nearest = self.nearest_threat(x, y);
+        if nearest < self.threat_radius {
+            (self.threat_radius - nearest) * self.cost_threat
         } else {
-            warn!("there is no data to return after tracing completes")
+            0
         }
     }
 }
+
+
+/// finds the cheapest paths to all given nodes, the search stops when all of them are found.
+/// For each node `found` stores the position where it was reached and the cost of the path to it
+struct FloodGoal {
+    nodes: Vec<PointGoal>,
+    found: RefCell<Vec<Option<(Position, isize)>>>,
+    remaining: Cell<usize>,
+}
+
+impl FloodGoal {
+    fn new(nodes: &[(isize, isize, i8)], options: &TraceOptions, heuristic: Heuristic) -> Self {
+        Self {
+            nodes: nodes.iter().map(|&(x, y, z)| PointGoal {
+                x,
+                y,
+                z,
+                accuracy_x: options.accuracy_x.unwrap_or(0),
+                accuracy_y: options.accuracy_y.unwrap_or(0),
+                accuracy_z: options.accuracy_z.unwrap_or(0),
+                heuristic,
+            }).collect(),
+            found: RefCell::new(vec![None; nodes.len()]),
+            remaining: Cell::new(nodes.len()),
+        }
+    }
+}
+
+impl SearchGoal for FloodGoal {
+    fn is_reached(&self, position: &Position, gval: isize) -> bool {
+        let mut found = self.found.borrow_mut();
+        for (node, found) in self.nodes.iter().zip(found.iter_mut()) {
+            if found.is_none() && node.is_reached(position, gval) {
+                *found = Some((*position, gval));
+                self.remaining.set(self.remaining.get() - 1);
+            }
+        }
+
+        self.remaining.get() == 0
+    }
+
+    // positions are explored strictly in order of cost, so the first match is the cheapest
+    fn estimate(&self, _position: &Position, _gval: isize) -> isize {
+        0
+    }
+
+    fn distance(&self, _position: &Position, _gval: isize) -> isize {
+        0
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::WorldModel;
+    use crate::world::world_model::FacetSpec;
+    use crate::world::test_data::TestData;
+
+    fn grass(data: &TestData) -> WorldModel {
+        data.write_world(0, 4, 4, &[]);
+        data.model(&[FacetSpec { world: 0, width: 4, height: 4, format: None, files: None, wrap: false }])
+    }
+
+    fn point(x: isize, y: isize) -> Point {
+        Point { x, y, z: 0, w: 0 }
+    }
+
+    #[test]
+    fn intercept_goal_moves_with_time() {
+        let goal = InterceptGoal {
+            x: 10, y: 10, z: 0,
+            vx: 0.2, vy: 0.0,
+            accuracy_x: 0, accuracy_y: 0, accuracy_z: 0,
+            heuristic: Heuristic::from_options(&TraceOptions::empty()),
+        };
+
+        assert!(goal.is_reached(&Position(10, 10, 0), 0));
+        assert!(!goal.is_reached(&Position(10, 10, 0), 10));
+        assert!(goal.is_reached(&Position(12, 10, 0), 10));
+        assert_eq!(goal.distance(&Position(10, 10, 0), 10), 2);
+    }
+
+    #[test]
+    fn intercept_meets_the_target_on_its_way() {
+        let data = TestData::new();
+        let model = grass(&data);
+        let surveyor = WorldSurveyor::new(model.world(0).unwrap());
+        let options = TraceOptions::empty();
+
+        // the target stands still, the path leads to it, the points end one step before the goal
+        let mut points = Vec::new();
+        let trace = surveyor.trace_intercept(4, 8, 0, 0, &[point(24, 8)], 1.0, &mut points, &options);
+        assert_eq!(trace.status, TraceStatus::Reached);
+        assert_eq!(points.last().map(|p| (p.x, p.y)), Some((23, 8)));
+
+        // the target comes towards us as fast as we go, we meet in the middle.
+        // The turn at the start shifts the time by one, so we would pass each other without accuracy
+        let mut points = Vec::new();
+        let options = TraceOptions { accuracy_x: Some(1), ..TraceOptions::empty() };
+        let trace = surveyor.trace_intercept(4, 8, 0, 0, &[point(25, 8), point(24, 8)], 1.0, &mut points, &options);
+        assert_eq!(trace.status, TraceStatus::Reached);
+        let end = points.last().unwrap();
+        assert!((12..=16).contains(&end.x) && end.y == 8, "met at {}, {}", end.x, end.y);
+
+        assert_eq!(surveyor.trace_intercept(4, 8, 0, 0, &[], 1.0, &mut points, &options).status, TraceStatus::NoData);
+    }
+
+    #[test]
+    fn flee_goal_penalizes_the_threat_radius() {
+        let goal = FleeGoal {
+            threats: vec![(10, 10), (30, 30)],
+            distance: 5,
+            threat_radius: 8,
+            cost_threat: 2,
+            heuristic: Heuristic::from_options(&TraceOptions::empty()),
+        };
+
+        assert!(goal.is_reached(&Position(15, 10, 0), 0));
+        assert!(!goal.is_reached(&Position(14, 12, 0), 0));
+        assert_eq!(goal.distance(&Position(12, 10, 0), 0), 3);
+        assert_eq!(goal.penalty(10, 12), 12);
+        assert_eq!(goal.penalty(18, 10), 0);
+    }
+
+    #[test]
+    fn flee_runs_away_from_threats() {
+        let data = TestData::new();
+        let model = grass(&data);
+        let surveyor = WorldSurveyor::new(model.world(0).unwrap());
+        let options = TraceOptions::empty();
+
+        let mut points = Vec::new();
+        let threats = [point(10, 16), point(16, 10)];
+        let trace = surveyor.trace_flee(14, 14, 0, 0, &threats, 8, 8, &mut points, &options);
+        assert_eq!(trace.status, TraceStatus::Reached);
+
+        // the points end one step before the goal
+        let end = points.last().unwrap();
+        for threat in &threats {
+            assert!((end.x - threat.x).abs().max((end.y - threat.y).abs()) >= 7, "ended at {}, {}", end.x, end.y);
+        }
+
+        // already far enough
+        let mut points = Vec::new();
+        let trace = surveyor.trace_flee(30, 30, 0, 0, &threats, 8, 8, &mut points, &options);
+        assert_eq!(trace.status, TraceStatus::Reached);
+        assert!(points.len() <= 1);
+    }
+}
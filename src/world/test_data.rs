@@ -0,0 +1,139 @@
+// synthetic data files for the tests: a small world of flat grass at z 0 with a few kinds of statics
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::world::world_model::{FacetSpec, WorldModel};
+
+pub const GRASS: u16 = 3;
+// static tiles
+pub const WALL: u16 = 1;    // impassable, 20 high
+pub const FLOOR: u16 = 2;   // surface, 0 high
+pub const WINDOW: u16 = 4;  // impassable window, 20 high, it blocks the sight as walls do
+
+const LAND_GROUP_SIZE: usize = 4 + 32 * 26;
+const STATIC_GROUP_SIZE: usize = 4 + 32 * 37;
+const MAP_BLOCK_SIZE: usize = 4 + 64 * 3;
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+
+/// folder with the data files shared by all worlds, it is removed when dropped
+pub struct TestData {
+    pub path: PathBuf,
+}
+
+impl TestData {
+    pub fn new() -> Self {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!("path_server_test_{}_{id}", std::process::id()));
+        fs::create_dir_all(&path).unwrap();
+
+        let result = Self { path };
+        result.write("tiledata.mul", &Self::tiledata());
+        result.write("radarcol.mul", &[0; 0x10000]);
+
+        // a single multi-object of one part, so the version of multi.mul can be determined
+        result.write("multi.idx", &[0, 0, 0, 0, 12, 0, 0, 0, 0, 0, 0, 0]);
+        result.write("multi.mul", &[0; 12]);
+        result
+    }
+
+    pub fn write(&self, file_name: &str, data: &[u8]) {
+        fs::write(self.path.join(file_name), data).unwrap();
+    }
+
+    /// old format, only the tiles used by the tests have flags
+    fn tiledata() -> Vec<u8> {
+        let mut data = vec![0; 512 * LAND_GROUP_SIZE + STATIC_GROUP_SIZE];
+        let statics = &mut data[512 * LAND_GROUP_SIZE..];
+
+        let (impassable, wall, surface, window) = (0x40, 0x10, 0x200, 0x1000);
+        for (tile, flags, height) in [(WALL, impassable | wall, 20), (FLOOR, surface, 0), (WINDOW, impassable | window, 20)] {
+            let offset = 4 + tile as usize * 37;
+            statics[offset..offset + 4].copy_from_slice(&(flags as u32).to_le_bytes());
+            statics[offset + 16] = height;
+        }
+
+        data
+    }
+
+    /// writes map, staidx and statics files of flat grass at z 0, `statics` are (x, y, z, tile)
+    pub fn write_world(&self, world: u8, width_blocks: usize, height_blocks: usize, statics: &[(usize, usize, i8, u16)]) {
+        let blocks = width_blocks * height_blocks;
+
+        let mut map = Vec::with_capacity(blocks * MAP_BLOCK_SIZE);
+        for _ in 0..blocks {
+            map.extend_from_slice(&Self::land_block(GRASS, 0));
+        }
+        self.write(&format!("map{world}.mul"), &map);
+
+        let mut index = Vec::with_capacity(blocks * 12);
+        let mut tiles = Vec::new();
+        for bx in 0..width_blocks {
+            for by in 0..height_blocks {
+                let block: Vec<_> = statics.iter()
+                    .filter(|&&(x, y, ..)| x / 8 == bx && y / 8 == by)
+                    .collect();
+
+                let offset = if block.is_empty() { u32::MAX } else { tiles.len() as u32 };
+                index.extend_from_slice(&offset.to_le_bytes());
+                index.extend_from_slice(&(block.len() as u32 * 7).to_le_bytes());
+                index.extend_from_slice(&0u32.to_le_bytes());
+
+                for &&(x, y, z, tile) in &block {
+                    tiles.extend_from_slice(&Self::static_tile(x, y, z, tile));
+                }
+            }
+        }
+
+        self.write(&format!("staidx{world}.mul"), &index);
+        self.write(&format!("statics{world}.mul"), &tiles);
+    }
+
+    /// a block of the map of the same land tile at the same z
+    pub fn land_block(tile: u16, z: i8) -> Vec<u8> {
+        let mut block = vec![0; 4];
+        for _ in 0..64 {
+            block.extend_from_slice(&tile.to_le_bytes());
+            block.push(z as u8);
+        }
+
+        block
+    }
+
+    /// a static tile as stored in statics and stadif files
+    pub fn static_tile(x: usize, y: usize, z: i8, tile: u16) -> Vec<u8> {
+        let mut data = tile.to_le_bytes().to_vec();
+        data.extend_from_slice(&[(x % 8) as u8, (y % 8) as u8, z as u8, 0, 0]);
+        data
+    }
+
+    /// contents of verdata.mul with the patches (file_id, block_id, data), the data follows the list of records
+    pub fn verdata(patches: &[(u32, u32, Vec<u8>)]) -> Vec<u8> {
+        let mut result = (patches.len() as u32).to_le_bytes().to_vec();
+        let mut offset = 4 + patches.len() * 20;
+        for (file_id, block_id, data) in patches {
+            for value in [*file_id, *block_id, offset as u32, data.len() as u32, 0] {
+                result.extend_from_slice(&value.to_le_bytes());
+            }
+            offset += data.len();
+        }
+
+        for (_, _, data) in patches {
+            result.extend_from_slice(data);
+        }
+
+        result
+    }
+
+    pub fn model(&self, facets: &[FacetSpec]) -> WorldModel {
+        WorldModel::new(&self.path, facets).unwrap()
+    }
+}
+
+impl Drop for TestData {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
@@ -123,8 +123,8 @@ pub enum TileShape {
 
 impl TileShape {
 
-    pub fn from_static_tile(z: i8, static_tile: &StaticTileData, walkable: u32, ignore: u32) -> Self {
-        let mut passable = static_tile.flags & MulTileFlags::Impassable as u32 == 0;
+    pub fn from_static_tile(z: i8, static_tile: &StaticTileData, walkable: u64, ignore: u64) -> Self {
+        let mut passable = static_tile.flags & MulTileFlags::Impassable as u64 == 0;
 
         let z_base = z;
         let height = static_tile.height as i8;
@@ -133,13 +133,13 @@ impl TileShape {
         // HoverOver flag is used for Gargoyle Flight Paths
         // there are only 3 tiles in standard tiledata with this flag and they all have a height of 0
         // that's why i use only one coordinate
-        if (static_tile.flags & (MulTileFlags::HoverOver as u32)) != 0 {
+        if (static_tile.flags & (MulTileFlags::HoverOver as u64)) != 0 {
             return Self::hover_over(z_base)
         }
 
         // this tile does not affect the movement in any way,
         // since it is not blocking and at the same time it is not a surface that can be walked on
-        if (static_tile.flags & (MulTileFlags::Impassable as u32 | MulTileFlags::Surface as u32 | walkable)) == 0 {
+        if (static_tile.flags & (MulTileFlags::Impassable as u64 | MulTileFlags::Surface as u64 | walkable)) == 0 {
             return Self::background(z_base, z_top)
         };
 
@@ -153,7 +153,7 @@ impl TileShape {
             passable = true;
         }
 
-        if (static_tile.flags & MulTileFlags::Bridge as u32) == 0 {
+        if (static_tile.flags & MulTileFlags::Bridge as u64) == 0 {
             let z_stand = z_top;
             Self::flat(z_base, z_stand, passable)
         } else {
@@ -162,8 +162,8 @@ impl TileShape {
         }
     }
 
-    pub fn from_land_tile(z_base: i8, z_stand: i8, z_top: i8, tile: u16, land_tile: &LandTileData, walkable: u32) -> Self {
-        let mut passable = (land_tile.flags & MulTileFlags::Impassable as u32) == 0;
+    pub fn from_land_tile(z_base: i8, z_stand: i8, z_top: i8, tile: u16, land_tile: &LandTileData, walkable: u64) -> Self {
+        let mut passable = (land_tile.flags & MulTileFlags::Impassable as u64) == 0;
 
         // special land tiles, which, as it were, do not exist
         if tile == 0x0002 || tile == 0x01DB || (tile >= 0x01AE && tile <= 0x01B5)  {
@@ -204,6 +204,24 @@ impl TileShape {
 
 }
 
+/// where a tile of the world comes from
+#[derive(Serialize, Deserialize, PartialEq, Eq, Copy, Clone, Debug)]
+pub enum TileSource {
+    Land,
+    Static,
+    Dynamic,
+    Multi,
+}
+
+/// a tile with its source, used to inspect the contents of the world.
+/// `serial` is the serial of the game object or of the parent multi-object, for dynamic tiles
+#[derive(Copy, Clone, Debug)]
+pub struct SourcedTile {
+    pub source: TileSource,
+    pub serial: Option<u32>,
+    pub tile: WorldTile,
+}
+
 /// base representation of the tile, stores information about the type of tile and its number
 /// and also stores information that is used when checking the movement
 #[derive(Copy, Clone, Debug)]
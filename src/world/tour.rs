@@ -0,0 +1,180 @@
+/// the visiting order of the tour nodes, node 0 is always the starting point and is not included in `order`
+pub struct Solution {
+    pub order: Vec<usize>,
+    pub unreachable: Vec<usize>,
+    pub broken: Vec<usize>,     // positions in `order` of the nodes without a path from the previous node, `order.len()` is the return to node 0
+    pub cost: isize,            // total cost of the legs which have a path
+}
+
+// weight of a leg without a path, any number of such legs is worse than any total cost of the legs with paths
+const BROKEN_LEG: i128 = 1 << 64;
+
+#[inline]
+fn leg(costs: &[Vec<Option<isize>>], from: usize, to: usize) -> i128 {
+    costs[from][to].map_or(BROKEN_LEG, |cost| cost as i128)
+}
+
+/// searches for a good order of visiting all nodes using the `costs` matrix, where `costs[i][j]`
+/// is the cost of the path from node i to node j, or None if there is no path.
+/// The matrix may be asymmetric. Nodes unreachable from the starting node are excluded from the tour,
+/// the order minimizes the number of the legs without a path first, then the total cost
+pub fn solve(costs: &[Vec<Option<isize>>], closed: bool) -> Solution {
+    let (mut remaining, unreachable): (Vec<usize>, Vec<usize>) = (1..costs.len())
+        .partition(|&node| costs[0][node].is_some());
+
+    // nearest neighbour
+    let mut order = Vec::with_capacity(remaining.len());
+    let mut from = 0;
+    while !remaining.is_empty() {
+        let (index, _) = remaining.iter()
+            .enumerate()
+            .min_by_key(|&(_, &to)| leg(costs, from, to))
+            .unwrap();
+
+        from = remaining.remove(index);
+        order.push(from);
+    }
+
+    // 2-opt, reverse parts of the tour while it gets cheaper. In an asymmetric matrix the cost
+    // of the reversed part changes too, so the sums of its legs in both directions are kept while it grows
+    let n = order.len();
+    let mut improved = true;
+    while improved {
+        improved = false;
+        for i in 0..n {
+            let prev = if i == 0 { 0 } else { order[i - 1] };
+            let (mut forward, mut backward) = (0, 0);
+            for k in i + 1..n {
+                forward += leg(costs, order[k - 1], order[k]);
+                backward += leg(costs, order[k], order[k - 1]);
+
+                let next = match order.get(k + 1) {
+                    Some(&next) => Some(next),
+                    None if closed => Some(0),
+                    None => None,
+                };
+                let next_leg = |from: usize| next.map_or(0, |next| leg(costs, from, next));
+
+                let old = leg(costs, prev, order[i]) + forward + next_leg(order[k]);
+                let new = leg(costs, prev, order[k]) + backward + next_leg(order[i]);
+                if new < old {
+                    order[i..=k].reverse();
+                    std::mem::swap(&mut forward, &mut backward);
+                    improved = true;
+                }
+            }
+        }
+    }
+
+    let mut broken = Vec::new();
+    let mut cost = 0;
+    let mut from = 0;
+    let legs = order.iter().copied().chain(closed.then_some(0));
+    for (position, to) in legs.enumerate() {
+        match costs[from][to] {
+            Some(leg_cost) => cost += leg_cost,
+            None => broken.push(position),
+        }
+        from = to;
+    }
+
+    Solution {
+        order,
+        unreachable,
+        broken,
+        cost,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matrix(rows: &[&[i64]]) -> Vec<Vec<Option<isize>>> {
+        rows.iter()
+            .map(|row| row.iter().map(|&cost| if cost < 0 { None } else { Some(cost as isize) }).collect())
+            .collect()
+    }
+
+    #[test]
+    fn open_tour_follows_the_line() {
+        // nodes on a line 0 - 2 - 1 - 3, the cost is the distance
+        let costs = matrix(&[
+            &[0, 2, 1, 3],
+            &[2, 0, 1, 1],
+            &[1, 1, 0, 2],
+            &[3, 1, 2, 0],
+        ]);
+
+        let solution = solve(&costs, false);
+        assert_eq!(solution.order, vec![2, 1, 3]);
+        assert_eq!(solution.cost, 3);
+        assert!(solution.broken.is_empty());
+        assert!(solution.unreachable.is_empty());
+    }
+
+    #[test]
+    fn closed_tour_includes_return() {
+        let costs = matrix(&[
+            &[0, 1, 2, 1],
+            &[1, 0, 1, 2],
+            &[2, 1, 0, 1],
+            &[1, 2, 1, 0],
+        ]);
+
+        let solution = solve(&costs, true);
+        assert_eq!(solution.cost, 4);
+        assert_eq!(solution.order.len(), 3);
+        assert!(solution.broken.is_empty());
+    }
+
+    #[test]
+    fn asymmetric_costs_choose_the_cheap_direction() {
+        // going around 0 -> 1 -> 2 -> 3 -> 0 is cheap, the opposite direction is expensive
+        let costs = matrix(&[
+            &[0, 1, 5, 9],
+            &[9, 0, 1, 5],
+            &[5, 9, 0, 1],
+            &[1, 5, 9, 0],
+        ]);
+
+        let solution = solve(&costs, true);
+        assert_eq!(solution.order, vec![1, 2, 3]);
+        assert_eq!(solution.cost, 4);
+    }
+
+    #[test]
+    fn unreachable_nodes_are_excluded() {
+        let costs = matrix(&[
+            &[0, 1, -1],
+            &[1, 0, -1],
+            &[-1, -1, 0],
+        ]);
+
+        let solution = solve(&costs, true);
+        assert_eq!(solution.order, vec![1]);
+        assert_eq!(solution.unreachable, vec![2]);
+        assert_eq!(solution.cost, 2);
+        assert!(solution.broken.is_empty());
+    }
+
+    #[test]
+    fn broken_legs_are_avoided_and_reported() {
+        // 2 is reachable from 0, but there is no way back from it to anywhere
+        let costs = matrix(&[
+            &[0, 1, 1],
+            &[1, 0, 1],
+            &[-1, -1, 0],
+        ]);
+
+        let open = solve(&costs, false);
+        assert_eq!(open.order, vec![1, 2]);
+        assert!(open.broken.is_empty());
+        assert_eq!(open.cost, 2);
+
+        let closed = solve(&costs, true);
+        assert_eq!(closed.broken, vec![2]);
+        assert_eq!(closed.cost, 2);
+    }
+}
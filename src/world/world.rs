@@ -1,15 +1,19 @@
 use crate::http::server::Item;
 use crate::mapdata::LandBlock;
 use crate::staticdata::StaticTile;
-use crate::world::tiles::DynamicWorldObject;
+use crate::world::tiles::{DynamicWorldObject, SourcedTile, TileSource};
+use crate::world::navmesh::NavMesh;
+use crate::world::step_cache::StepCache;
+use crate::world::step_table::StepTable;
+use crate::world::world_model::FacetFormat;
 use crate::world::{TileShape, TileType};
 use crate::*;
 use crate::mulreader::{get_world_file_path};
-use std::fs;
 
-use log::{debug, trace, warn};
+use log::{debug, info, trace, warn};
 use std::collections::hash_map::Entry;
-use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::io::ErrorKind;
+use std::sync::{OnceLock, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
 /// Basic World representation
 /// Stores world size information in XxY blocks and also stores information
@@ -22,57 +26,93 @@ pub struct StaticWorld {
     height: usize,
     pub land: Land,      // source of land data
     pub statics: Static, // source of static data
+    pub patched: bool,   // mapdif and stadif patches are applied
+    pub file_index: u8,  // N in the names of the data files, map{N}.mul, statics{N}.mul and so on
+    pub use_mul: bool,   // the map is read from map{N}.mul, otherwise from map{N}LegacyMUL.uop
+    pub wrap: bool,      // the world is wrapped around at the edges, searches step over them
 }
 
 impl StaticWorld {
+    /// reads the land and statics, `world` is the number in the names of the data files
     pub fn read(
         data_path: &Path,
         world: u8,
         use_mul: bool,
         width_blocks: usize,
         height_blocks: usize,
-    ) -> Self {
+    ) -> Result<Self, MulError> {
         let land = if use_mul {
-            Land::read_mul(data_path, world, width_blocks, height_blocks).unwrap()
+            Land::read_mul(data_path, world, width_blocks, height_blocks)?
         } else {
-            Land::read_uop(data_path, world, width_blocks, height_blocks).unwrap()
+            Land::read_uop(data_path, world, width_blocks, height_blocks)?
         };
 
-        Self {
+        Ok(Self {
             width_blocks,
             height_blocks,
             width: width_blocks * 8,
             height: height_blocks * 8,
             land,
-            statics: Static::read(data_path, world, width_blocks, height_blocks).unwrap(),
+            statics: Static::read(data_path, world, width_blocks, height_blocks)?,
+            patched: false,
+            file_index: world,
+            use_mul,
+            wrap: false,
+        })
+    }
+
+    /// applies mapdif and stadif patches to the land and statics, if there are patch files for the world
+    pub fn apply_patches(&mut self, data_path: &Path, world: u8) {
+        match self.land.apply_patches(data_path, self.file_index) {
+            Ok(0) => {}
+            Ok(blocks) => {
+                info!("{blocks} map blocks of world {world} are patched");
+                self.patched = true;
+            }
+            Err(err) => warn!("failed to apply map patches of world {world}: {err}"),
+        }
+
+        match self.statics.apply_patches(data_path, self.file_index) {
+            Ok(0) => {}
+            Ok(blocks) => {
+                info!("{blocks} statics blocks of world {world} are patched");
+                self.patched = true;
+            }
+            Err(err) => warn!("failed to apply statics patches of world {world}: {err}"),
         }
     }
 
     /// checks for the existence of data files and returns the type and dimensions of the world.
-    /// true means old format MUL, false means new format UOP.
+    /// true means old format MUL, false means new format UOP. Returns None if there are no files of the world.
+    /// If `format` is None, MUL is preferred, otherwise the map file of this format must exist.
+    /// The format applies only to the map, statics are read from statics{N}.mul if it exists, from the UOP file otherwise
     pub fn probe(
         data_path: &Path,
         world: u8,
+        format: Option<FacetFormat>,
         width: usize,
         height: usize,
-    ) -> Option<(bool, usize, usize)> {
-        match fs::metadata(get_world_file_path(data_path, "map", world, ".mul")) {
-            Ok(_) => {
-                let width = Land::calc_mul_width(data_path, world, height);
+    ) -> Result<Option<(bool, usize, usize)>, MulError> {
+        let mul_path = get_world_file_path(data_path, "map", world, ".mul");
+        let uop_path = get_world_file_path(data_path, "map", world, "LegacyMUL.uop");
+
+        match format {
+            Some(FacetFormat::Mul) | None if mul_path.exists() => {
+                let width = Land::calc_mul_width(data_path, world, height)?;
                 debug!("found MUL file for world {world} with size {width}x{height} blocks");
-                Some((true, width, height))
+                Ok(Some((true, width, height)))
             }
 
-            Err(_) => match fs::metadata(get_world_file_path(data_path, "map", world, "LegacyMUL.uop")) {
-                Ok(_) => {
-                    let width = Land::calc_uop_width(data_path, world, height);
-                    debug!("found UOP file for world {world} with size {width}x{height} blocks");
-                    Some((false, width, height))
-                }
-                Err(_) => None,
-            },
-        }
+            Some(FacetFormat::Uop) | None if uop_path.exists() => {
+                let width = Land::calc_uop_width(data_path, world, height)?;
+                debug!("found UOP file for world {world} with size {width}x{height} blocks");
+                Ok(Some((false, width, height)))
+            }
 
+            None => Ok(None),
+            Some(FacetFormat::Mul) => Err(MulError::io(&mul_path, ErrorKind::NotFound.into())),
+            Some(FacetFormat::Uop) => Err(MulError::io(&uop_path, ErrorKind::NotFound.into())),
+        }
     }
 
     #[inline]
@@ -220,6 +260,9 @@ type ReadCache<'a> = RwLockReadGuard<'a, OverlayCache>;
 pub struct DynamicWorld {
     pub data: Arc<WorldData>,
     pub base: StaticWorld,
+    pub navmesh: NavMesh,
+    pub step_cache: StepCache,
+    pub step_table: OnceLock<StepTable>,
     overlay_blocks: OverlayCacheLock,
 }
 
@@ -231,14 +274,17 @@ impl DynamicWorld {
         use_mul: bool,
         width_blocks: usize,
         height_blocks: usize,
-    ) -> Self {
+    ) -> Result<Self, MulError> {
         let result = DynamicWorld {
             data: world_data,
-            base: StaticWorld::read(data_path, world, use_mul, width_blocks, height_blocks),
+            base: StaticWorld::read(data_path, world, use_mul, width_blocks, height_blocks)?,
+            navmesh: NavMesh::new(),
+            step_cache: StepCache::new(),
+            step_table: OnceLock::new(),
             overlay_blocks: RwLock::new(HashMap::new()),
         };
 
-        result
+        Ok(result)
     }
 
     #[inline]
@@ -251,12 +297,26 @@ impl DynamicWorld {
         self.overlay_blocks.read().unwrap()
     }
 
+    /// drops cached navigation blocks and step results which may depend on the tile x, y.
+    /// steps from the neighbouring blocks may lead into the tile, so they are dropped too
+    fn invalidate_caches(&self, x: isize, y: isize) {
+        let (x, y) = self.base.normalize_tiles(x, y);
+        for bx in x / 8 - 1..=x / 8 + 1 {
+            for by in y / 8 - 1..=y / 8 + 1 {
+                let index = self.base.block_index(bx, by);
+                self.navmesh.invalidate(index);
+                self.step_cache.invalidate(index);
+            }
+        }
+    }
+
     fn overlay_insert_item(&self, overlay: &mut WriteCache, item: DynamicWorldObject) {
         let (x, y) = match item {
             DynamicWorldObject::MultiPart { x, y, .. }
             | DynamicWorldObject::GameObject { x, y, .. } => (x, y),
         };
         let (block_index, _) = self.base.tile_to_block_offsets(x, y);
+        self.invalidate_caches(x, y);
 
         match overlay.entry(block_index) {
             Entry::Occupied(mut entry) => {
@@ -277,6 +337,7 @@ impl DynamicWorld {
             | DynamicWorldObject::GameObject { x, y, .. } => (x, y),
         };
         let (block_index, _) = self.base.tile_to_block_offsets(x, y);
+        self.invalidate_caches(x, y);
 
         match overlay.entry(block_index) {
             Entry::Occupied(mut v) => {
@@ -451,7 +512,7 @@ impl DynamicWorld {
     }
 
     #[inline]
-    pub fn world_tile_flag(&self, tile: &WorldTile) -> u32 {
+    pub fn world_tile_flag(&self, tile: &WorldTile) -> u64 {
         let tiledata = &self.data.tiledata;
         match tile.tile {
             TileType::MapTile(num) => tiledata.get_land_tile(num).flags,
@@ -469,7 +530,7 @@ impl DynamicWorld {
     }
 
     /// returns a `WorldTile` structure for the map tile, given the direction of travel.
-    pub fn query_tile_ground(&self, x: isize, y: isize, direction: u8, walkable: u32) -> WorldTile {
+    pub fn query_tile_ground(&self, x: isize, y: isize, direction: u8, walkable: u64) -> WorldTile {
         let (idx, (ox, oy)) = self.base.tile_to_block_offsets(x, y);
         let tiledata = &self.data.tiledata;
 
@@ -497,8 +558,8 @@ impl DynamicWorld {
         &self,
         x: isize,
         y: isize,
-        walkable: u32,
-        ignore: u32,
+        walkable: u64,
+        ignore: u64,
         result: &mut Vec<WorldTile>,
     ) {
         let (idx, (ox, oy)) = self.base.tile_to_block_offsets(x, y);
@@ -527,8 +588,8 @@ impl DynamicWorld {
         &self,
         x: isize,
         y: isize,
-        walkable: u32,
-        ignore: u32,
+        walkable: u64,
+        ignore: u64,
         result: &mut Vec<WorldTile>,
     ) {
         let (idx, (_ox, _oy)) = self.base.tile_to_block_offsets(x, y);
@@ -590,8 +651,8 @@ impl DynamicWorld {
         x: isize,
         y: isize,
         direction: u8,
-        walkable: u32,
-        ignore: u32,
+        walkable: u64,
+        ignore: u64,
         result: &mut Vec<WorldTile>,
     ) {
         result.push(self.query_tile_ground(x, y, direction, walkable));
@@ -606,6 +667,43 @@ impl DynamicWorld {
         })
     }
 
+    /// adds to `result` all tiles located in the specified tile with their sources: land, statics,
+    /// game objects and parts of multi-objects, sorted as in `query_tile_full`. No extended flags are applied
+    pub fn query_tile_sources(&self, x: isize, y: isize, result: &mut Vec<SourcedTile>) {
+        let mut tiles = Vec::with_capacity(16);
+
+        result.push(SourcedTile { source: TileSource::Land, serial: None, tile: self.query_tile_ground(x, y, 0, 0) });
+
+        self.query_tile_static(x, y, 0, 0, &mut tiles);
+        result.extend(tiles.drain(..).map(|tile| SourcedTile { source: TileSource::Static, serial: None, tile }));
+
+        let (idx, _) = self.base.tile_to_block_offsets(x, y);
+        let tiledata = &self.data.tiledata;
+        let overlay = self.read_overlay();
+        if let Some(block) = overlay.get(&idx) {
+            for item in block.range(DynamicWorldObject::min_item(x, y)..=DynamicWorldObject::max_item(x, y)) {
+                let (source, serial, tile, z) = match *item {
+                    DynamicWorldObject::MultiPart { tile, parent, z, .. } => (TileSource::Multi, parent, tile, z),
+                    DynamicWorldObject::GameObject { graphic, serial, z, .. } => (TileSource::Dynamic, serial, graphic, z),
+                };
+                if tile & 0x30000 != 0 {
+                    continue; // skip multi-objects
+                }
+
+                result.push(SourcedTile {
+                    source,
+                    serial: Some(serial),
+                    tile: WorldTile {
+                        tile: TileType::ObjectTile(tile as u16),
+                        shape: TileShape::from_static_tile(z, tiledata.get_static_tile(tile as u16), 0, 0),
+                    },
+                });
+            }
+        }
+
+        result.sort_by(|a, b| a.tile.z_base().cmp(&b.tile.z_base()).then(a.tile.z_top().cmp(&b.tile.z_top())));
+    }
+
     /// searches for game objects in the specified area. Parts of multi-objects are ignored
     pub fn query_area_dynamic(
         &self,
@@ -1,15 +1,17 @@
 use std::collections::HashMap;
 use std::path::Path;
-use std::fs::File;
-use std::io::{Read, Write};
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read, Write};
+use std::str::FromStr;
 use std::sync::{Arc, RwLock};
-use log::{debug, warn};
+use log::{debug, error, info, warn};
 use crate::world::DynamicWorld;
 
 use tokio::time::Instant;
 use crate::http::server::{Item, MultiItemPart};
 use crate::mul::colordata::ColorData;
-use crate::mul::{Multi, TileData};
+use crate::mul::{Multi, MulError, TileData};
+use crate::mul::verdata::Verdata;
 use crate::world::tiles::TopLevelItem;
 use crate::world::world::StaticWorld;
 
@@ -26,13 +28,138 @@ pub struct WorldData {
 }
 
 impl WorldData {
-    pub fn new(data_path: &Path) -> Self {
-        WorldData {
-            colors: ColorData::read(data_path).unwrap(),
-            tiledata: TileData::read(data_path).unwrap(),
-            multis: Multi::read(data_path).unwrap(),
+    pub fn new(data_path: &Path) -> Result<Self, MulError> {
+        let mut tiledata = TileData::read(data_path)?;
+        let mut multis = Multi::read(data_path)?;
+
+        // patches of legacy clients, newer clients have them merged into the data files
+        match Verdata::read(data_path) {
+            Ok(Some(verdata)) => {
+                match tiledata.apply_verdata(&verdata) {
+                    Ok(count) => info!("{count} tiledata groups are patched from {}", verdata.path().display()),
+                    Err(err) => warn!("unable to apply tiledata patches from {}: {err}", verdata.path().display()),
+                }
+
+                match multis.apply_verdata(&verdata) {
+                    Ok(count) => info!("{count} multi-objects are patched from {}", verdata.path().display()),
+                    Err(err) => warn!("unable to apply multi patches from {}: {err}", verdata.path().display()),
+                }
+            }
+
+            Ok(None) => {}
+            Err(err) => warn!("unable to read verdata: {err}"),
+        }
+
+        Ok(WorldData {
+            colors: ColorData::read(data_path)?,
+            tiledata,
+            multis,
             custom_multis: RwLock::new(HashMap::new()),
+        })
+    }
+}
+
+
+/// format of the map files of a facet, statics are found independently of it
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FacetFormat {
+    Mul,
+    Uop,
+}
+
+/// description of a facet (world) and its data files
+#[derive(Debug, Clone, Deserialize)]
+pub struct FacetSpec {
+    pub world: u8,
+    pub width: usize,   // expected width in blocks, the actual width is calculated from the size of the map
+    pub height: usize,  // height in blocks
+    #[serde(default)]
+    pub format: Option<FacetFormat>,    // None means MUL if there is map{N}.mul, otherwise UOP
+    #[serde(default)]
+    pub files: Option<u8>,  // N in the names of the data files, map{N}.mul, statics{N}.mul and so on, `world` by default
+    #[serde(default)]
+    pub wrap: bool,         // the facet is wrapped around at the edges, stepping off the east edge leads to the west edge
+}
+
+impl FacetSpec {
+    /// facets of the original client
+    pub fn defaults() -> Vec<Self> {
+        [(0, 768, 512), (1, 768, 512), (2, 288, 200), (3, 320, 256), (4, 181, 181), (5, 160, 512)]
+            .into_iter()
+            .map(|(world, width, height)| FacetSpec { world, width, height, format: None, files: None, wrap: false })
+            .collect()
+    }
+
+    #[inline]
+    pub fn file_index(&self) -> u8 {
+        self.files.unwrap_or(self.world)
+    }
+
+    /// reads a JSON array of facets, e.g. [{"world": 6, "width": 896, "height": 512, "format": "uop"}]
+    pub fn read_config(path: &Path) -> Result<Vec<Self>, std::io::Error> {
+        let specs: Vec<Self> = serde_json::from_str(&fs::read_to_string(path)?)?;
+        for spec in &specs {
+            spec.validate().map_err(|err| std::io::Error::new(ErrorKind::InvalidData, err))?;
+        }
+
+        Ok(specs)
+    }
+
+    /// replaces the facets with the same world or adds new ones, the result is ordered by world
+    pub fn merge(specs: &mut Vec<Self>, overrides: Vec<Self>) {
+        for spec in overrides {
+            specs.retain(|curr| curr.world != spec.world);
+            specs.push(spec);
         }
+
+        specs.sort_by_key(|spec| spec.world);
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.width == 0 || self.height == 0 {
+            return Err(format!("facet {} has zero size", self.world))
+        }
+
+        Ok(())
+    }
+}
+
+/// parses "world:width:height[:format[:files[:wrap]]]", e.g. "6:896:512" or "7:1448:1448:uop:2:wrap", format is mul, uop or auto
+impl FromStr for FacetSpec {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = value.split(':').collect();
+        if parts.len() < 3 || parts.len() > 6 {
+            return Err(format!("expected world:width:height[:format[:files[:wrap]]], got {value}"))
+        }
+
+        let number = |part: &str| part.parse::<usize>().map_err(|err| format!("invalid number {part}: {err}"));
+        let index = |part: &str| part.parse::<u8>().map_err(|err| format!("invalid index {part}: {err}"));
+
+        let format = match parts.get(3).copied() {
+            None | Some("auto") => None,
+            Some("mul") => Some(FacetFormat::Mul),
+            Some("uop") => Some(FacetFormat::Uop),
+            Some(other) => return Err(format!("unknown format {other}, expected mul, uop or auto")),
+        };
+
+        let spec = FacetSpec {
+            world: index(parts[0])?,
+            width: number(parts[1])?,
+            height: number(parts[2])?,
+            format,
+            files: parts.get(4).map(|part| index(part)).transpose()?,
+            wrap: match parts.get(5).copied() {
+                None => false,
+                Some("wrap") => true,
+                Some(other) => return Err(format!("unknown option {other}, expected wrap")),
+            },
+        };
+
+        spec.validate()?;
+        Ok(spec)
     }
 }
 
@@ -53,42 +180,81 @@ pub struct WorldModel {
 }
 
 impl WorldModel {
-    pub fn new(data_path: &Path) -> Self {
+    /// loads the data shared by all worlds and the facets whose files are found.
+    /// Fails if the shared data can't be read, a facet with broken files is skipped
+    pub fn new(data_path: &Path, facets: &[FacetSpec]) -> Result<Self, MulError> {
         let mut result = WorldModel {
-            data: Arc::new(WorldData::new(data_path)),
+            data: Arc::new(WorldData::new(data_path)?),
             worlds: Vec::new(),
 
             items_index: RwLock::new(HashMap::new()),
         };
 
-        let world_specs = [(0, 768, 512), (1, 768, 512), (2, 288, 200), (3, 320, 256), (4, 181, 181), (5, 160, 512)];
-        for (world, w,h) in world_specs {
-            match StaticWorld::probe(data_path, world, w, h) {
-                Some((use_mul, w, h)) => {
-                    result.worlds.push(Some(DynamicWorld::new(data_path, result.data.clone(), world, use_mul, w, h)));
+        for spec in facets {
+            let world = spec.world;
+            let files = spec.file_index();
+            if result.worlds.len() <= world as usize {
+                result.worlds.resize_with(world as usize + 1, || None);
+            }
+
+            let loaded = StaticWorld::probe(data_path, files, spec.format, spec.width, spec.height).and_then(|probe| match probe {
+                Some((use_mul, w, h)) => DynamicWorld::new(data_path, result.data.clone(), files, use_mul, w, h).map(Some),
+                None => Ok(None),
+            });
+
+            match loaded {
+                Ok(Some(mut curr_world)) => {
+                    curr_world.base.wrap = spec.wrap;
+                    result.worlds[world as usize] = Some(curr_world);
                     debug!("world {world} is loaded");
                 }
 
-                None => {
-                    result.worlds.push(None);
+                Ok(None) => {
                     debug!("No files found for world {world}")
                 }
+
+                Err(err) => {
+                    error!("world {world} is skipped: {err}");
+                }
             }
         }
 
-        result
+        Ok(result)
+    }
+
+
+    /// applies mapdif and stadif patches to all loaded worlds, where the patch files exist.
+    /// Must be called before step tables are loaded and any items are added
+    pub fn apply_patches(&mut self, data_path: &Path) {
+        for (world, curr_world) in self.worlds.iter_mut().enumerate() {
+            if let Some(curr_world) = curr_world {
+                curr_world.base.apply_patches(data_path, world as u8);
+            }
+        }
+    }
+
+
+    /// opens precomputed step tables of all loaded worlds, missing or outdated ones are built if `build` is set.
+    /// Must be called before any items are added
+    pub fn load_step_tables(&self, data_path: &Path, build: bool) {
+        for (world, curr_world) in self.worlds.iter().enumerate() {
+            if let Some(curr_world) = curr_world {
+                curr_world.load_step_table(data_path, world as u8, build);
+            }
+        }
     }
 
 
     pub fn next_world_idx(&self, idx: u8) -> u8 {
-        let len = self.worlds.len() as u8;
-        for i in (idx+1..len).chain(0..idx+1) {
-            if self.worlds[i as usize].is_some() {
-                return i;
+        // counted in usize, there may be 256 worlds
+        let (len, idx) = (self.worlds.len(), idx as usize);
+        for i in (idx + 1..len).chain(0..=idx) {
+            if self.world(i as u8).is_some() {
+                return i as u8;
             }
         }
 
-        idx
+        idx as u8
     }
 
 
@@ -132,7 +298,10 @@ impl WorldModel {
         }
 
         for (_, item) in ws.items_index {
-            self.insert_item(item);
+            let (serial, world) = (item.serial, item.world);
+            if !self.insert_item(item) {
+                warn!("item {serial} is skipped, world {world} is not loaded");
+            }
         }
 
         debug!("world state loaded in {:?}", start_time.elapsed());
@@ -162,41 +331,56 @@ impl WorldModel {
         let item = index.get(&serial);
 
         if let Some(&TopLevelItem{ world, x, y, z, serial, graphic, .. }) = item {
-            let world = self.world(world).unwrap();
-            world.delete_item(x, y, z, serial, graphic);
+            // items of worlds which are not loaded never get into the index, but the index must not panic under the lock
+            if let Some(world) = self.world(world) {
+                world.delete_item(x, y, z, serial, graphic);
+            }
             index.remove(&serial);
         }
 
     }
 
 
-    pub fn insert_item(&self, item: TopLevelItem) {
+    /// returns false if the world of the item is not loaded, the item is not inserted then
+    pub fn insert_item(&self, item: TopLevelItem) -> bool {
+        let world_model = match self.world(item.world) {
+            Some(world_model) => world_model,
+            None => return false,
+        };
+
         let mut index = self.items_index.write().unwrap();
 
         // delete old item
         let old = index.remove(&item.serial);
         if let Some(TopLevelItem{ world, x, y, z, serial, graphic , .. }) = old {
-            let world_model = self.world(world).unwrap();
-            world_model.delete_item(x, y, z, serial, graphic);
+            if let Some(world) = self.world(world) {
+                world.delete_item(x, y, z, serial, graphic);
+            }
         }
 
-        let world_model = self.world(item.world).unwrap();
         world_model.insert_item(item.x, item.y, item.z, item.serial, item.graphic, );
 
         // insert new
         index.insert(item.serial, item);
-
+        true
     }
 
 
-    pub fn insert_multi_item(&self, item: TopLevelItem, parts: &Vec<MultiItemPart>) {
+    /// returns false if the world of the item is not loaded, the item is not inserted then
+    pub fn insert_multi_item(&self, item: TopLevelItem, parts: &Vec<MultiItemPart>) -> bool {
+        let world_model = match self.world(item.world) {
+            Some(world_model) => world_model,
+            None => return false,
+        };
+
         let mut index = self.items_index.write().unwrap();
 
         // try delete main item from index
         let old = index.remove(&item.serial);
         if let Some(TopLevelItem{ world, x, y, z, serial, graphic, .. }) = old {
-            let world_model = self.world(world).unwrap();
-            world_model.delete_item(x, y, z, serial, graphic);
+            if let Some(world) = self.world(world) {
+                world.delete_item(x, y, z, serial, graphic);
+            }
         }
 
         // update custom_multis parts
@@ -206,15 +390,19 @@ impl WorldModel {
         }
 
         // insert multi-parts to the world
-        let world_model = self.world(item.world).unwrap();
         world_model.insert_item(item.x, item.y, item.z, item.serial, item.graphic);
 
         index.insert(item.serial, item);    // insert main item to index
+        true
     }
 
 
-    pub fn query(&self, world: u8, left: isize, top: isize, right: isize, bottom: isize, items: &mut Vec<Item>) {
-        let d_world = self.world(world).unwrap();
+    /// returns false if the world is not loaded
+    pub fn query(&self, world: u8, left: isize, top: isize, right: isize, bottom: isize, items: &mut Vec<Item>) -> bool {
+        let d_world = match self.world(world) {
+            Some(d_world) => d_world,
+            None => return false,
+        };
 
         let s = Instant::now();
         d_world.query_area_dynamic(world, left, top, right, bottom, items);
@@ -229,5 +417,93 @@ impl WorldModel {
         }
 
         debug!("({left}, {top})-({right}, {bottom}) found {} items at {:?}", items.len(), s.elapsed());
+        true
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::test_data::TestData;
+
+    fn facet(world: u8) -> FacetSpec {
+        FacetSpec { world, width: 2, height: 2, format: None, files: None, wrap: false }
+    }
+
+    #[test]
+    fn items_of_missing_worlds_are_rejected() {
+        let data = TestData::new();
+        data.write_world(0, 2, 2, &[]);
+        // world 2 has no files and is skipped, world 7 is not configured at all
+        let model = data.model(&[facet(0), facet(2)]);
+
+        assert!(!model.insert_item(TopLevelItem::new(2, 5, 5, 0, 1, 0x0E75, 0)));
+        assert!(!model.insert_item(TopLevelItem::new(7, 5, 5, 0, 2, 0x0E75, 0)));
+        assert!(!model.insert_multi_item(TopLevelItem::new(7, 5, 5, 0, 3, 0x0E75, 0), &vec![]));
+        assert!(model.insert_item(TopLevelItem::new(0, 5, 5, 0, 4, 0x0E75, 0)));
+
+        let mut items = Vec::new();
+        assert!(!model.query(7, 0, 0, 15, 15, &mut items));
+        assert!(model.query(0, 0, 0, 15, 15, &mut items));
+        assert_eq!(items.len(), 1);
+
+        // the index is not poisoned and contains only the inserted item
+        model.delete_item(2);
+        assert_eq!(model.items_index.read().unwrap().keys().copied().collect::<Vec<_>>(), vec![4]);
+    }
+
+    fn fields(spec: &FacetSpec) -> (u8, usize, usize, Option<FacetFormat>, Option<u8>, bool) {
+        (spec.world, spec.width, spec.height, spec.format, spec.files, spec.wrap)
+    }
+
+    #[test]
+    fn facets_are_parsed() {
+        let parse = |value: &str| value.parse::<FacetSpec>().map(|spec| fields(&spec));
+
+        assert_eq!(parse("6:896:512"), Ok((6, 896, 512, None, None, false)));
+        assert_eq!(parse("7:1448:1448:uop:2:wrap"), Ok((7, 1448, 1448, Some(FacetFormat::Uop), Some(2), true)));
+        assert_eq!(parse("1:10:20:mul"), Ok((1, 10, 20, Some(FacetFormat::Mul), None, false)));
+        assert_eq!(parse("1:10:20:auto:3"), Ok((1, 10, 20, None, Some(3), false)));
+
+        for wrong in ["6:896", "6:896:512:uop:2:wrap:x", "256:1:1", "1:x:1", "1:0:1", "1:1:-1", "1:1:1:zip", "1:1:1:mul:1:round"] {
+            assert!(parse(wrong).is_err(), "{wrong} is accepted");
+        }
+    }
+
+    #[test]
+    fn facets_are_merged_by_world() {
+        let mut specs = FacetSpec::defaults();
+        let overrides = ["7:100:100", "2:300:210:uop", "7:110:110"].iter().map(|value| value.parse().unwrap()).collect();
+        FacetSpec::merge(&mut specs, overrides);
+
+        assert_eq!(specs.iter().map(|spec| spec.world).collect::<Vec<_>>(), vec![0, 1, 2, 3, 4, 5, 7]);
+        assert_eq!(fields(&specs[2]), (2, 300, 210, Some(FacetFormat::Uop), None, false));
+        assert_eq!(fields(&specs[6]), (7, 110, 110, None, None, false));
+        assert_eq!(fields(&specs[0]), (0, 768, 512, None, None, false));
+    }
+
+    #[test]
+    fn facets_are_read_from_config() {
+        let data = TestData::new();
+        data.write("facets.json", br#"[{"world": 6, "width": 896, "height": 512, "format": "uop", "wrap": true}]"#);
+        let specs = FacetSpec::read_config(&data.path.join("facets.json")).unwrap();
+        assert_eq!(specs.iter().map(fields).collect::<Vec<_>>(), vec![(6, 896, 512, Some(FacetFormat::Uop), None, true)]);
+
+        data.write("facets.json", br#"[{"world": 6, "width": 0, "height": 512}]"#);
+        let err = FacetSpec::read_config(&data.path.join("facets.json")).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn next_world_skips_missing_ones() {
+        let data = TestData::new();
+        data.write_world(0, 2, 2, &[]);
+        data.write_world(255, 2, 2, &[]);
+        let model = data.model(&[facet(0), facet(2), facet(255)]);
+
+        assert_eq!(model.next_world_idx(0), 255);
+        assert_eq!(model.next_world_idx(2), 255);
+        assert_eq!(model.next_world_idx(255), 0);
     }
 }